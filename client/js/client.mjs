@@ -1,12 +1,48 @@
-import { client } from "@mercuryworkshop/wisp-js/client";
-const { ClientConnection } = client;
+import { randomBytes } from "node:crypto";
 
 let server_port = parseInt(process.argv[2]);
 let target_port = parseInt(process.argv[3]);
 let stream_count = parseInt(process.argv[4]);
+let payload_size = parseInt(process.argv[5] || 1024 * 50);
+let pattern = process.argv[6] || "echo";
+let transport = process.argv[7] || "ws";
+let compression = process.argv[8] || "off";
+let payload_mode = process.argv[9] || "compressible";
+
+if (transport === "wss") {
+  // wispmark serves a self-signed certificate
+  process.env.NODE_TLS_REJECT_UNAUTHORIZED = "0";
+}
+
+if (compression === "on") {
+  // the built in WebSocket never offers permessage-deflate, so swap in ws
+  // before wisp-js picks up the global
+  const { WebSocket: NodeWebSocket } = await import("ws");
+  globalThis.WebSocket = class extends NodeWebSocket {
+    constructor(url, protocols) {
+      super(url, protocols, { perMessageDeflate: true, rejectUnauthorized: false });
+    }
+  };
+}
 
-let ws_url = `ws://127.0.0.1:${server_port}/`;
-let payload = new TextEncoder().encode("a".repeat(1024 * 50));
+const { client } = await import("@mercuryworkshop/wisp-js/client");
+const { ClientConnection } = client;
+
+let ws_url = `${transport}://127.0.0.1:${server_port}/`;
+let compressible = new TextEncoder().encode("a".repeat(payload_size));
+// deflate remembers the last 32 KiB, so random payloads are cut from a pool
+// much larger than that instead of resending the same bytes
+let pool = payload_mode === "incompressible"
+  ? new Uint8Array(randomBytes(Math.max(16 * 1024 * 1024, payload_size * 4)))
+  : null;
+let offset = 0;
+function next_payload() {
+  if (!pool) return compressible;
+  if (offset + payload_size > pool.length) offset = 0;
+  let payload = pool.subarray(offset, offset + payload_size);
+  offset += payload_size;
+  return payload;
+}
 let max_buffered = 5 * 1024 * 1024;
 
 console.log(`connecting to ${ws_url}`)
@@ -15,13 +51,14 @@ conn.onopen = () => {
   console.log(`connected, opening ${stream_count} streams`)
   for (let i=0; i < stream_count; i++) {
     let stream = conn.create_stream("127.0.0.1", target_port);
-    
+    if (pattern === "download") continue;
+
     setInterval(() => {
       if (stream.send_buffer.length < 20 && conn.ws.bufferedAmount < max_buffered) {
         for (let j = 0; j < 10; j++) {
-          stream.send(payload);
+          stream.send(next_payload());
         }
       }
     }, 0);
   } 
-}; 
\ No newline at end of file
+};
@@ -8,6 +8,7 @@ const server = createServer((req, res) => {
 logging.set_level(logging.DEBUG);
 wisp.options.allow_private_ips = true;
 wisp.options.allow_loopback_ips = true;
+Object.assign(wisp.options, JSON.parse(process.argv[3] || "{}"));
 
 server.on("upgrade", (req, socket, head) => {
   wisp.routeRequest(req, socket, head);
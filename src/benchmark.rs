@@ -1,250 +1,729 @@
-use crate::structure::{BenchmarkResult, BenchmarkResults, WispClient, WispServer};
-use crate::{client, echo, server, util};
-use anyhow::Result;
-use std::path::PathBuf;
-use std::process::Child;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::time::{sleep, Duration};
-
-fn kill(child: &mut Child) {
-    let _ = child.kill();
-    let _ = child.wait();
+use crate::echo::StreamBytes;
+use crate::fairness::Fairness;
+use crate::report::{Checkpoint, ResultEntry};
+use crate::structure::{
+    Bandwidth, BenchmarkResult, BenchmarkResults, BenchmarkSettings, Compression, LoadShape,
+    Repeats, RunHandle, RunOptions, Transport, WispClient, WispServer,
+};
+use crate::system::SystemInfo;
+use crate::{client, echo, impair, loadgen, manage, noise, placement, server, tls, util};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct BaselineResults {
+    pub bandwidths: Vec<(String, RunOptions, Bandwidth)>,
+    /// Clients with the same shape share one measurement.
+    #[serde(default)]
+    pub shapes: Vec<(LoadShape, Bandwidth)>,
 }
 
-pub struct BaselineResults {
-    pub bandwidths: Vec<(String, f64)>,
+impl BaselineResults {
+    pub fn get(&self, client: &str, options: &RunOptions) -> Option<Bandwidth> {
+        self.bandwidths
+            .iter()
+            .find(|(name, opts, _)| {
+                name == client
+                    && opts.payload_size == options.payload_size
+                    && opts.pattern == options.pattern
+            })
+            .map(|(_, _, bw)| *bw)
+    }
+
+    pub fn shape(&self, shape: &LoadShape) -> Option<Bandwidth> {
+        self.shapes
+            .iter()
+            .find(|(s, _)| s == shape)
+            .map(|(_, bw)| *bw)
+    }
 }
 
-pub async fn benchmark(test: u64) -> Result<(BenchmarkResults, Option<BaselineResults>)> {
-    let mut echo_process = echo::run_echo()?;
-    util::wait_for_tcp(util::ECHO_PORT, util::SERVER_TIMEOUT).await?;
+pub fn servers(settings: &BenchmarkSettings) -> Vec<Box<dyn WispServer>> {
+    server::get_implementations(&settings.server_options)
+        .into_iter()
+        .filter(|s| util::name_matches(s.name(), &settings.servers))
+        .collect()
+}
 
-    let clients = client::get_implementations();
-    
-    let baseline_results = match baseline(test, &clients).await {
-        Ok(b) => Some(b),
-        Err(e) => {
-            eprintln!("Warning: baseline latency measurement failed: {}", e);
-            None
+pub fn clients(settings: &BenchmarkSettings) -> Vec<Box<dyn WispClient>> {
+    client::get_matrix(&settings.streams, &settings.instances)
+        .into_iter()
+        .filter(|c| util::name_matches(c.name(), &settings.clients))
+        .collect()
+}
+
+// returns false when the run was interrupted before every pair finished
+pub async fn run_pairs(
+    settings: &BenchmarkSettings,
+    servers: Vec<Box<dyn WispServer>>,
+    clients: Vec<Box<dyn WispClient>>,
+    checkpoint: &mut Checkpoint,
+) -> Result<bool> {
+    if settings.transports.contains(&Transport::Wss) {
+        tls::generate_certificate()?;
+        let proxied = &mut checkpoint.file.tls_proxied;
+        for server in servers.iter().filter(|s| !s.native_tls()) {
+            if !proxied.iter().any(|name| name == server.name()) {
+                proxied.push(server.name().to_string());
+            }
         }
-    };
+    }
 
-    let servers = server::get_implementations();
+    if servers.is_empty() || clients.is_empty() {
+        bail!("No server or client matches the selected implementations");
+    }
 
-    for server in &servers {
-        if !server.check_install() {
-            println!("Installing {}", server.name());
-            server.install()?;
-        }
+    tokio::fs::create_dir_all(util::log_dir()).await?;
+
+    // before installing, so builds don't show up in the load average
+    let mut system = SystemInfo::collect();
+    noise::check(settings.max_noise, settings.abort_when_busy).await?;
+    let install_failed = manage::install_missing(&servers, &clients, settings.jobs);
+    for name in &install_failed {
+        eprintln!(
+            "{} is not installed, its results will be reported as failed",
+            name
+        );
+    }
+    system.record_versions(&servers, &clients);
+    checkpoint.record_system(system)?;
+
+    if let Some(impairment) = &settings.impairment {
+        println!("Impairing the client link: {}", impairment);
+    }
+    let _placement = placement::activate(&settings.placement)?;
+    if !settings.placement.is_empty() {
+        println!("Placement: {}", settings.placement);
     }
 
-    for client in &clients {
-        if !client.check_install() {
-            println!("Installing {}", client.name());
-            client.install()?;
+    tokio::select! {
+        result = run_matrix(settings, &servers, &clients, &install_failed, checkpoint) => {
+            result?;
+            println!("WispMark has finished.");
+            Ok(true)
+        }
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!("\nInterrupted, stopping the running pair...");
+            if let Err(e) = util::kill(util::WISP_PORT) {
+                eprintln!("Warning: Failed to stop the server: {}", e);
+            }
+            Ok(false)
         }
     }
+}
 
-    let log_dir = util::base().join("log");
-    tokio::fs::create_dir_all(&log_dir).await?;
-    let mut results = BenchmarkResults::new();
-    for server in &servers {
-        let server_name = server.name().to_string();
+async fn run_matrix(
+    settings: &BenchmarkSettings,
+    servers: &[Box<dyn WispServer>],
+    clients: &[Box<dyn WispClient>],
+    install_failed: &HashSet<String>,
+    checkpoint: &mut Checkpoint,
+) -> Result<()> {
+    let test = settings.duration;
+    let payload_sizes = &settings.payload_sizes;
+    let patterns = &settings.patterns;
+    let transports = &settings.transports;
+    let compressions = &settings.compressions;
+    let payload_modes = &settings.payload_modes;
+
+    let log_dir = util::log_dir();
+    let sweep = payload_sizes.len()
+        * patterns.len()
+        * transports.len()
+        * compressions.len()
+        * payload_modes.len()
+        > 1;
+
+    for &pattern in patterns.iter() {
+        let mut options = Vec::new();
+        for &transport in transports {
+            for &compression in compressions {
+                for &payload_mode in payload_modes {
+                    for &payload_size in payload_sizes {
+                        options.push(RunOptions {
+                            payload_size,
+                            pattern,
+                            transport,
+                            compression,
+                            payload_mode,
+                        });
+                    }
+                }
+            }
+        }
 
-        for client in &clients {
-            let client_name = client.name().to_string();
-            println!("Testing {} with {}", server_name, client_name);
+        let done = |s: &str, c: &str, o: &RunOptions| checkpoint.file.find(s, c, o).is_some();
+        let pending = servers.iter().any(|s| {
+            clients
+                .iter()
+                .any(|c| options.iter().any(|o| !done(s.name(), c.name(), o)))
+        });
+        if !pending {
+            println!("Skipping the {} pattern, already completed", pattern);
+            continue;
+        }
+
+        let target = echo::run_target(pattern, settings.fairness)?;
+        util::wait_for_tcp(util::ECHO_PORT, util::SERVER_TIMEOUT).await?;
+
+        if settings.baseline {
+            if let Err(e) = baseline(settings, clients, &options, checkpoint).await {
+                eprintln!("Warning: baseline measurement failed: {}", e);
+            }
+        }
 
-            let server_log = log_dir.join(format!("SERVER_{}_{}.log", server_name, client_name));
-            let client_log = log_dir.join(format!("CLIENT_{}_{}.log", server_name, client_name));
+        for server in servers {
+            let server_name = server.name().to_string();
+            let server_file = util::log_name(&server_name);
 
-            let result = single(
-                server.as_ref(),
-                client.as_ref(),
-                &server_log,
-                &client_log,
-                test,
-            )
-            .await;
+            for client in clients {
+                let client_name = client.name().to_string();
 
-            results.add(server_name.clone(), client_name.clone(), result);
+                for opts in &options {
+                    if checkpoint
+                        .file
+                        .find(&server_name, &client_name, opts)
+                        .is_some()
+                    {
+                        continue;
+                    }
+                    let suffix = if sweep {
+                        format!(
+                            "_{}_{}_deflate-{}_{}_{}",
+                            opts.pattern,
+                            opts.transport,
+                            opts.compression,
+                            opts.payload_mode,
+                            opts.payload_size
+                        )
+                    } else {
+                        String::new()
+                    };
+                    let server_log = log_dir
+                        .join(format!("SERVER_{}_{}{}.log", server_file, client_name, suffix));
+                    let client_log = log_dir
+                        .join(format!("CLIENT_{}_{}{}.log", server_file, client_name, suffix));
+
+                    let deflate_ok =
+                        opts.compression == Compression::Off || server.supports_deflate();
+                    let mut repeats = None;
+                    let (result, fairness, noise) = if install_failed.contains(&server_name)
+                        || install_failed.contains(&client_name)
+                    {
+                        failed("Install failed")
+                    } else if deflate_ok && client.supports(opts) {
+                        let mut runs = Vec::with_capacity(settings.repeats);
+                        let mut fairness = Vec::new();
+                        let mut noise = Vec::new();
+                        for run in 1..=settings.repeats {
+                            let repeat = if settings.repeats > 1 {
+                                format!(" (run {}/{})", run, settings.repeats)
+                            } else {
+                                String::new()
+                            };
+                            println!(
+                                "Testing {} with {} ({}, {} payloads){}",
+                                server_name,
+                                client_name,
+                                opts.section(),
+                                util::format_size(opts.payload_size),
+                                repeat
+                            );
+                            let run_suffix = if settings.repeats > 1 {
+                                format!("_run{}", run)
+                            } else {
+                                String::new()
+                            };
+                            let (result, streams, external) = single(
+                                server.as_ref(),
+                                client.as_ref(),
+                                settings,
+                                opts,
+                                &with_suffix(&server_log, &run_suffix),
+                                &with_suffix(&client_log, &run_suffix),
+                                test,
+                            )
+                            .await;
+                            runs.push(result);
+                            fairness.extend(streams);
+                            noise.extend(external);
+                        }
+                        repeats = Repeats::of(&runs);
+                        (
+                            combine(runs),
+                            Fairness::combine(&fairness),
+                            noise.into_iter().reduce(f64::max),
+                        )
+                    } else {
+                        failed("Unsupported")
+                    };
+
+                    checkpoint.record(ResultEntry {
+                        server: server_name.clone(),
+                        client: client_name.clone(),
+                        options: *opts,
+                        result,
+                        fairness,
+                        noise,
+                        repeats,
+                    })?;
+                }
+            }
         }
+
+        drop(target);
     }
 
-    kill(&mut echo_process);
-    println!("WispMark has finished.");
+    Ok(())
+}
 
-    Ok((results, baseline_results))
+fn with_suffix(log: &Path, suffix: &str) -> PathBuf {
+    let stem = log.file_stem().unwrap_or_default().to_string_lossy();
+    log.with_file_name(format!("{}{}.log", stem, suffix))
 }
 
-async fn baseline(test: u64, clients: &[Box<dyn WispClient>]) -> Result<BaselineResults> {    
-    let mut bandwidths = Vec::new();
-    
+// repeated runs are averaged over the ones that succeeded
+fn combine(runs: Vec<BenchmarkResult>) -> BenchmarkResult {
+    let bandwidths: Vec<Bandwidth> = runs
+        .iter()
+        .filter_map(|r| match r {
+            BenchmarkResult::Success(bw) => Some(*bw),
+            BenchmarkResult::Failed(_) => None,
+        })
+        .collect();
+    if bandwidths.is_empty() {
+        return runs
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| BenchmarkResult::Failed("No runs".to_string()));
+    }
+    let n = bandwidths.len() as f64;
+    BenchmarkResult::Success(Bandwidth {
+        up: bandwidths.iter().map(|bw| bw.up).sum::<f64>() / n,
+        down: bandwidths.iter().map(|bw| bw.down).sum::<f64>() / n,
+    })
+}
+
+// the raw TCP baseline only depends on the payload size and pattern, so every client is measured
+// once per size and pattern, with the first combination of the other options it supports
+async fn baseline(
+    settings: &BenchmarkSettings,
+    clients: &[Box<dyn WispClient>],
+    options: &[RunOptions],
+    checkpoint: &mut Checkpoint,
+) -> Result<()> {
     for client in clients {
         let client_name = client.name().to_string();
-        println!("Measuring baseline bandwidth for {}s...", test);
-
-        let (instances, streams) = client_config(&client_name);
-        let total_connections = instances * streams;
-        
-        let mut handles = Vec::new();
-        for _ in 0..total_connections {
-            let handle = tokio::spawn(async move {
-                let buffer = vec![0u8; 8192];
-                loop {
-                    if let Ok(mut stream) = TcpStream::connect(format!("127.0.0.1:{}", util::ECHO_PORT)).await {
-                        let mut read_buf = vec![0u8; 8192];
-                        loop {
-                            match stream.write_all(&buffer).await {
-                                Ok(_) => {}
-                                Err(_) => break,
-                            }
-                            match stream.read(&mut read_buf).await {
-                                Ok(0) => break,
-                                Ok(_) => {}
-                                Err(_) => break,
-                            }
-                        }
-                    }
-                    sleep(Duration::from_millis(10)).await;
-                }
-            });
-            handles.push(handle);
-        }
 
-        sleep(Duration::from_secs(1)).await;
+        for opts in options {
+            let known = checkpoint.file.baseline.as_ref();
+            if !client.supports(opts) || known.is_some_and(|b| b.get(&client_name, opts).is_some())
+            {
+                continue;
+            }
+            let mut shape = client.shape(opts);
+            if let Some(depth) = settings.baseline_depth {
+                shape.depth = depth;
+            }
+            if let Some(bandwidth) = known.and_then(|b| b.shape(&shape)) {
+                checkpoint.record_baseline(&client_name, *opts, shape, bandwidth)?;
+                continue;
+            }
+            println!(
+                "Measuring baseline bandwidth for {} ({}) for {}s...",
+                client_name, shape, settings.duration
+            );
+
+            let generator = loadgen::spawn(shape)?;
+            sleep(Duration::from_secs(1)).await;
+            let bandwidth = util::get_bandwidth(util::ECHO_PORT, settings.duration).await;
+            drop(generator);
+
+            let bandwidth = bandwidth?;
+            println!("Result: {}", bandwidth);
 
-        let bandwidth_bytes_per_sec = util::get_bandwidth(util::ECHO_PORT, test).await?;
-        let bandwidth_mib_s = bandwidth_bytes_per_sec / (1024.0 * 1024.0);
-        
-        for handle in handles {
-            handle.abort();
+            checkpoint.record_baseline(&client_name, *opts, shape, bandwidth)?;
         }
-        
-        println!("Result: {:.2} MiB/s", bandwidth_mib_s);
-        
-        bandwidths.push((client_name, bandwidth_mib_s));
     }
 
-    Ok(BaselineResults { bandwidths })
+    Ok(())
 }
 
-fn client_config(name: &str) -> (usize, usize) {
-    if let Some(start) = name.find('(') {
-        if let Some(end) = name.find(')') {
-            let config = &name[start + 1..end];
-            
-            if let Some(pos) = config.find('×').or_else(|| config.find('x')) {
-                let instances = config[..pos].trim().parse().unwrap_or(1);
-                let streams = config[pos + 1..].trim().parse().unwrap_or(10);
-                return (instances, streams);
-            } else {
-                let streams = config.trim().parse().unwrap_or(10);
-                return (1, streams);
-            }
-        }
+/// A started server with its clients connected to it.
+pub struct Pair {
+    pub server: Box<dyn RunHandle>,
+    pub clients: Box<dyn RunHandle>,
+    front: Vec<JoinHandle<Result<()>>>,
+}
+
+impl Pair {
+    pub async fn stop(self) {
+        stop(self.clients, "clients").await;
+        stop_front(self.front).await;
+        stop(self.server, "server").await;
     }
-    (1, 10)
 }
 
-async fn single(
+// the error is the reason shown in the report
+pub async fn start_pair(
     server: &dyn WispServer,
     client: &dyn WispClient,
-    server_log: &PathBuf,
-    client_log: &PathBuf,
-    test: u64,
-) -> BenchmarkResult {
+    settings: &BenchmarkSettings,
+    options: &RunOptions,
+    server_log: &Path,
+    client_log: &Path,
+) -> Result<Pair, &'static str> {
     if let Err(e) = util::kill(util::WISP_PORT) {
         eprintln!("Warning: Failed to stop existing server: {}", e);
     }
     sleep(Duration::from_secs(1)).await;
-    let mut server_process = match server.run(util::WISP_PORT, server_log) {
-        Ok(p) => p,
+    let native_tls = options.transport == Transport::Wss && server.native_tls();
+    if let Err(e) = server.prepare(util::WISP_PORT, options).await {
+        eprintln!("Error preparing server: {}", e);
+        return Err("Server failed to start");
+    }
+    let mut server_handle = match server.start(util::WISP_PORT, options, server_log).await {
+        Ok(handle) => handle,
         Err(e) => {
             eprintln!("Error starting server: {}", e);
-            return BenchmarkResult::Failed("Server failed to start".to_string());
+            return Err("Server failed to start");
         }
     };
 
-    if let Err(e) = util::wait_for_http(util::WISP_PORT, util::SERVER_TIMEOUT).await {
+    if let Err(e) = server_handle.ready().await {
         eprintln!("Error: Server not ready: {}", e);
-        kill(&mut server_process);
-        return BenchmarkResult::Failed("Server timeout".to_string());
+        stop(server_handle, "server").await;
+        return Err("Server timeout");
+    }
+
+    let mut client_port = util::WISP_PORT;
+    let mut front = Vec::new();
+    if options.transport == Transport::Wss && !native_tls {
+        match tls::spawn_proxy(util::TLS_PORT, client_port).await {
+            Ok(proxy) => {
+                front.push(proxy);
+                client_port = util::TLS_PORT;
+            }
+            Err(e) => {
+                eprintln!("Error starting TLS proxy: {}", e);
+                stop(server_handle, "server").await;
+                return Err("TLS proxy failed to start");
+            }
+        }
+    }
+    if let Some(impairment) = &settings.impairment {
+        match impair::spawn(util::RELAY_PORT, client_port, impairment.clone()).await {
+            Ok(relay) => {
+                front.push(relay);
+                client_port = util::RELAY_PORT;
+            }
+            Err(e) => {
+                eprintln!("Error starting impairment relay: {}", e);
+                stop_front(front).await;
+                stop(server_handle, "server").await;
+                return Err("Relay failed to start");
+            }
+        }
     }
 
-    let mut client_processes = match client.run(util::WISP_PORT, util::ECHO_PORT, client_log) {
-        Ok(p) => p,
+    let started = match client.prepare(options).await {
+        Ok(()) => {
+            client
+                .start(client_port, util::ECHO_PORT, options, client_log)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    let mut client_handle = match started {
+        Ok(handle) => handle,
         Err(e) => {
             eprintln!("Error starting clients: {}", e);
-            kill(&mut server_process);
-            return BenchmarkResult::Failed("Client failed to start".to_string());
+            stop_front(front).await;
+            stop(server_handle, "server").await;
+            return Err("Client failed to start");
         }
     };
+    if let Err(e) = client_handle.ready().await {
+        eprintln!("Error: Clients not ready: {}", e);
+        stop(client_handle, "clients").await;
+        stop_front(front).await;
+        stop(server_handle, "server").await;
+        return Err("Client failed to start");
+    }
+
+    Ok(Pair {
+        server: server_handle,
+        clients: client_handle,
+        front,
+    })
+}
+
+pub async fn single(
+    server: &dyn WispServer,
+    client: &dyn WispClient,
+    settings: &BenchmarkSettings,
+    options: &RunOptions,
+    server_log: &Path,
+    client_log: &Path,
+    test: u64,
+) -> (BenchmarkResult, Option<Fairness>, Option<f64>) {
+    let pair = match start_pair(server, client, settings, options, server_log, client_log).await
+    {
+        Ok(pair) => pair,
+        Err(reason) => return failed(reason),
+    };
 
     sleep(Duration::from_secs(1)).await;
+    let before = if settings.fairness {
+        stream_bytes().await
+    } else {
+        None
+    };
+    let started = Instant::now();
+    let snapshot = noise::Snapshot::take();
     println!("Recording speeds for {}s...", test);
     let result = match util::get_bandwidth(util::ECHO_PORT, test).await {
-        Ok(speed) => {
-            let mib_s = speed / (1024.0 * 1024.0);
-            println!("Result: {:.2} MiB/s", mib_s);
-            BenchmarkResult::Success(mib_s)
+        Ok(bandwidth) => {
+            println!("Result: {}", bandwidth);
+            BenchmarkResult::Success(bandwidth)
         }
         Err(e) => {
             eprintln!("Error measuring bandwidth: {}", e);
             BenchmarkResult::Failed("DNF".to_string())
         }
     };
+    let noise = snapshot.and_then(|s| s.external());
+    if let Some(noise) = noise.filter(|&n| n > settings.max_noise) {
+        eprintln!(
+            "Warning: Other processes used {:.0}% of the CPU time during the measurement, the result is flagged as noisy",
+            noise * 100.0
+        );
+    }
+
+    let fairness = match (&result, before) {
+        (BenchmarkResult::Success(_), Some(before)) => {
+            let elapsed = started.elapsed();
+            stream_bytes()
+                .await
+                .and_then(|after| Fairness::measure(&before, &after, options.pattern, elapsed))
+        }
+        _ => None,
+    };
+
+    pair.stop().await;
+    (result, fairness, noise)
+}
+
+async fn stream_bytes() -> Option<Vec<StreamBytes>> {
+    match echo::stream_bytes(util::STATS_PORT).await {
+        Ok(streams) => Some(streams),
+        Err(e) => {
+            eprintln!("Warning: Failed to read stream stats: {}", e);
+            None
+        }
+    }
+}
+
+fn failed(reason: &str) -> (BenchmarkResult, Option<Fairness>, Option<f64>) {
+    (BenchmarkResult::Failed(reason.to_string()), None, None)
+}
 
-    kill(&mut server_process);
-    for client in &mut client_processes {
-        kill(client);
+pub async fn stop(handle: Box<dyn RunHandle>, what: &str) {
+    if let Err(e) = handle.shutdown().await {
+        eprintln!("Warning: Failed to stop {}: {}", what, e);
     }
+}
 
-    result
+async fn stop_front(front: Vec<JoinHandle<Result<()>>>) {
+    for task in front {
+        task.abort();
+        let _ = task.await;
+    }
 }
 
-pub fn format_results(results: &BenchmarkResults, cpu_info: &str, test: u64, baseline_results: &Option<BaselineResults>) -> String {
+pub fn format_results(
+    results: &BenchmarkResults,
+    cpu_info: &str,
+    system: Option<&SystemInfo>,
+    settings: &BenchmarkSettings,
+    baseline_results: &Option<BaselineResults>,
+) -> String {
     let mut output = String::new();
 
-    output.push_str(&format!("CPU: {}\n\n", cpu_info));
-    output.push_str(&format!("Test duration: {}s\n", test));
-    let mut table = vec![vec!["".to_string()]];
-    for client in &results.client_order {
-        table[0].push(client.clone());
+    match system {
+        Some(system) => output.push_str(&system.markdown()),
+        None => output.push_str(&format!("CPU: {}\n\n", cpu_info)),
+    }
+    output.push_str(&format!("Test duration: {}s\n", settings.duration));
+    if let Some(impairment) = &settings.impairment {
+        output.push_str(&format!("Network: {}\n", impairment));
+    }
+    if !settings.placement.is_empty() {
+        output.push_str(&format!("Placement: {}\n", settings.placement));
+    }
+    output.push_str("Throughput: upstream / downstream\n");
+
+    let mut sections: Vec<String> = Vec::new();
+    for options in &results.option_order {
+        if !sections.contains(&options.section()) {
+            sections.push(options.section());
+        }
     }
 
+    for section in &sections {
+        let options: Vec<RunOptions> = results
+            .option_order
+            .iter()
+            .filter(|o| o.section() == *section)
+            .copied()
+            .collect();
+
+        if sections.len() > 1 {
+            output.push_str(&format!("\n## {}\n", section));
+        }
+
+        if let [options] = options.as_slice() {
+            output.push_str(&format!(
+                "Payload size: {}\n",
+                util::format_size(options.payload_size)
+            ));
+            output.push_str(&format_matrix(results, options, baseline_results));
+            continue;
+        }
+
+        if let Some(baseline) = baseline_results {
+            output.push_str("\n### baseline\n");
+            output.push_str(&format_payload_table(
+                results,
+                &options,
+                false,
+                |client, options| {
+                    vec![baseline
+                        .get(client, options)
+                        .map_or_else(|| "N/A".to_string(), |bw| bw.to_string())]
+                },
+            ));
+        }
+
+        for server in &results.server_order {
+            output.push_str(&format!(
+                "\n### {}\n",
+                server_label(results, server, &options[0])
+            ));
+            output.push_str(&format_payload_table(
+                results,
+                &options,
+                baseline_results.is_some(),
+                |client, options| result_cells(results, server, client, options, baseline_results),
+            ));
+        }
+    }
+
+    output
+}
+
+fn server_label(results: &BenchmarkResults, server: &str, options: &RunOptions) -> String {
+    if options.transport == Transport::Wss && results.tls_proxied.iter().any(|s| s == server) {
+        format!("{} (TLS via proxy)", server)
+    } else {
+        server.to_string()
+    }
+}
+
+fn format_matrix(
+    results: &BenchmarkResults,
+    options: &RunOptions,
+    baseline_results: &Option<BaselineResults>,
+) -> String {
+    let mut table = vec![client_header("", results, baseline_results.is_some())];
+
     if let Some(baseline) = baseline_results {
         let mut row = vec!["baseline".to_string()];
         for client in &results.client_order {
-            let bandwidth = baseline.bandwidths
-                .iter()
-                .find(|(name, _)| name == client)
-                .map(|(_, bw)| *bw)
-                .unwrap_or(0.0);
-            let result = format!("{:.2} MiB/s", bandwidth);
-            row.push(result);
+            row.push(
+                baseline
+                    .get(client, options)
+                    .map_or_else(|| "N/A".to_string(), |bw| bw.to_string()),
+            );
+            row.push(String::new());
         }
         table.push(row);
     }
 
     for server in &results.server_order {
-        let mut row = vec![server.clone()];
+        let mut row = vec![server_label(results, server, options)];
         for client in &results.client_order {
-            let result = results
-                .get(server, client)
-                .map(|r| r.to_string())
-                .unwrap_or_else(|| "N/A".to_string());
-            row.push(result);
+            row.extend(result_cells(
+                results,
+                server,
+                client,
+                options,
+                baseline_results,
+            ));
         }
         table.push(row);
     }
 
-    output.push_str(&format_table(&table));
-    output
+    format_table(&table)
+}
+
+fn format_payload_table(
+    results: &BenchmarkResults,
+    options: &[RunOptions],
+    percent: bool,
+    cells: impl Fn(&str, &RunOptions) -> Vec<String>,
+) -> String {
+    let mut table = vec![client_header("payload", results, percent)];
+
+    for options in options {
+        let mut row = vec![util::format_size(options.payload_size)];
+        for client in &results.client_order {
+            row.extend(cells(client, options));
+        }
+        table.push(row);
+    }
+
+    format_table(&table)
+}
+
+// every client gets a "% of baseline" column when there is a baseline to compare to
+fn client_header(first: &str, results: &BenchmarkResults, percent: bool) -> Vec<String> {
+    let mut header = vec![first.to_string()];
+    for client in &results.client_order {
+        header.push(client.clone());
+        if percent {
+            header.push("% of baseline".to_string());
+        }
+    }
+    header
+}
+
+fn result_cells(
+    results: &BenchmarkResults,
+    server: &str,
+    client: &str,
+    options: &RunOptions,
+    baseline_results: &Option<BaselineResults>,
+) -> Vec<String> {
+    let result = results.get(server, client, options);
+    let mut cells = vec![result.map_or_else(|| "N/A".to_string(), |r| r.to_string())];
+    if let Some(baseline) = baseline_results {
+        let percent = match (result, baseline.get(client, options)) {
+            (Some(BenchmarkResult::Success(bw)), Some(base)) if base.up + base.down > 0.0 => {
+                format!("{:.0}%", (bw.up + bw.down) / (base.up + base.down) * 100.0)
+            }
+            _ => "-".to_string(),
+        };
+        cells.push(percent);
+    }
+    cells
 }
 
-fn format_table(table: &[Vec<String>]) -> String {
+pub fn format_table(table: &[Vec<String>]) -> String {
     let mut col_widths = vec![0; table[0].len()];
     for row in table {
         for (i, cell) in row.iter().enumerate() {
@@ -0,0 +1,321 @@
+use crate::config::{self, Config, RunArgs, RunConfig};
+use crate::faults::{self, FaultArgs};
+use crate::loadgen::{self, LoadgenArgs};
+use crate::report::{self, Checkpoint, ResultsFile};
+use crate::scale::{self, ScaleArgs};
+use crate::soak::{self, SoakArgs};
+use crate::structure::{OutputFormat, TrafficPattern};
+use crate::{benchmark, doctor, echo, manage, offline, server, util, BenchmarkRunner};
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(name = "wispmark")]
+#[command(about = "A benchmarking tool for Wisp protocol implementations")]
+#[command(args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs,
+
+    #[arg(long, global = true)]
+    base_dir: Option<PathBuf>,
+
+    #[arg(long, global = true)]
+    offline: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the benchmark (the default when no command is given)
+    Run(Box<RunArgs>),
+    /// Show or change the persistent defaults
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Install or rebuild implementations
+    Install {
+        names: Vec<String>,
+
+        #[arg(long)]
+        jobs: Option<usize>,
+    },
+    /// Update implementations to the latest or a pinned upstream ref
+    Update {
+        names: Vec<String>,
+
+        #[arg(long = "ref")]
+        reference: Option<String>,
+
+        #[arg(long)]
+        jobs: Option<usize>,
+    },
+    /// Remove installed implementations
+    Clean { names: Vec<String> },
+    /// List implementations with their install status and version
+    List,
+    /// Ramp streams and connections to find where each server stops scaling
+    Scale(Box<ScaleArgs>),
+    /// Run each server for hours and warn about leaks and throughput decay
+    Soak(Box<SoakArgs>),
+    /// Inject faults and malformed traffic and check that each server survives them
+    Faults(Box<FaultArgs>),
+    /// Compare two JSON result files
+    Compare { before: PathBuf, after: PathBuf },
+    /// Render a JSON result file as markdown
+    Report {
+        results: PathBuf,
+
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Check that everything needed for a run is available
+    Doctor,
+    /// Serve a benchmark target on its own
+    Echo {
+        #[arg(long, value_enum, default_value = "echo")]
+        pattern: TrafficPattern,
+
+        #[arg(long, default_value_t = util::ECHO_PORT)]
+        port: u16,
+
+        #[arg(long)]
+        stats_port: Option<u16>,
+    },
+    /// Send raw TCP load to a target, the traffic the baseline is measured with
+    Loadgen(LoadgenArgs),
+    /// Build an archive for offline installation
+    Fetch {
+        #[arg(long, default_value = "wispmark-offline.tar.gz")]
+        output: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    Get { key: Option<String> },
+    Set { key: String, value: String },
+    Unset { key: String },
+    Path,
+}
+
+fn resolve_base_dir(base_dir: Option<PathBuf>, config: &Config) -> Result<PathBuf> {
+    if let Some(dir) = base_dir {
+        Ok(dir)
+    } else if let Some(dir) = &config.base_dir {
+        println!("Using base directory: {}", dir.display());
+        Ok(dir.clone())
+    } else {
+        Ok(std::env::current_dir()?)
+    }
+}
+
+// the JSON file is written next to the markdown one, with its extension swapped
+fn check_output(run: &RunConfig) -> Result<()> {
+    let both =
+        run.formats.contains(&OutputFormat::Markdown) && run.formats.contains(&OutputFormat::Json);
+    if both && run.output.extension().is_some_and(|e| e == "json") {
+        bail!(
+            "--output {} would be overwritten by the JSON results, use a .md path",
+            run.output.display()
+        );
+    }
+    Ok(())
+}
+
+fn prepare_base_dir(base_dir: Option<PathBuf>, config: &Config) -> Result<()> {
+    let base_dir = resolve_base_dir(base_dir, config)?;
+    util::write_wispjs_files(&base_dir)?;
+    util::set_base_dir(base_dir)
+}
+
+/// Runs the `wispmark` command line with the process arguments.
+pub async fn main() -> Result<()> {
+    let args = Args::parse();
+    let config = config::load()?;
+
+    match args.command.unwrap_or(Command::Run(Box::new(args.run))) {
+        Command::Run(run_args) => {
+            run_benchmark(*run_args, args.base_dir, args.offline, config).await
+        }
+        Command::Config { action } => match action {
+            ConfigAction::Get { key } => {
+                println!("{}", config::get(key.as_deref())?);
+                Ok(())
+            }
+            ConfigAction::Set { key, value } => config::set(&key, &value),
+            ConfigAction::Unset { key } => config::unset(&key),
+            ConfigAction::Path => {
+                println!("{}", config::path().display());
+                Ok(())
+            }
+        },
+        Command::Install { names, jobs } => {
+            prepare_base_dir(args.base_dir, &config)?;
+            if let Some(source) = &args.offline {
+                util::set_mirror(offline::prepare(source)?)?;
+            }
+            manage::install(&names, jobs.unwrap_or(config.run.jobs))
+        }
+        Command::Update {
+            names,
+            reference,
+            jobs,
+        } => {
+            prepare_base_dir(args.base_dir, &config)?;
+            manage::update(
+                &names,
+                reference.as_deref(),
+                jobs.unwrap_or(config.run.jobs),
+            )
+        }
+        Command::Clean { names } => {
+            prepare_base_dir(args.base_dir, &config)?;
+            manage::clean(&names)
+        }
+        Command::List => {
+            prepare_base_dir(args.base_dir, &config)?;
+            manage::list()
+        }
+        Command::Scale(scale_args) => {
+            prepare_base_dir(args.base_dir, &config)?;
+            if let Some(source) = &args.offline {
+                util::set_mirror(offline::prepare(source)?)?;
+            }
+            echo::set_target_exe(std::env::current_exe()?);
+            scale::run(*scale_args, &config).await
+        }
+        Command::Soak(soak_args) => {
+            prepare_base_dir(args.base_dir, &config)?;
+            if let Some(source) = &args.offline {
+                util::set_mirror(offline::prepare(source)?)?;
+            }
+            echo::set_target_exe(std::env::current_exe()?);
+            soak::run(*soak_args, &config).await
+        }
+        Command::Faults(fault_args) => {
+            prepare_base_dir(args.base_dir, &config)?;
+            if let Some(source) = &args.offline {
+                util::set_mirror(offline::prepare(source)?)?;
+            }
+            faults::run(*fault_args, &config).await
+        }
+        Command::Compare { before, after } => {
+            let before = ResultsFile::load(&before)?;
+            let after = ResultsFile::load(&after)?;
+            println!("{}", report::compare(&before, &after));
+            Ok(())
+        }
+        Command::Report { results, output } => {
+            let markdown = ResultsFile::load(&results)?.markdown()?;
+            println!("{}", markdown);
+            if let Some(output) = output {
+                std::fs::write(&output, markdown)?;
+                println!("\nMarkdown results written to: {}", output.display());
+            }
+            Ok(())
+        }
+        Command::Doctor => {
+            util::set_base_dir(resolve_base_dir(args.base_dir, &config)?)?;
+            // only what a run with the stored defaults would use
+            let options = server::option_sets(&config.server_options, &[], &[])?;
+            let settings = config.run.settings(options)?;
+            doctor::run(
+                &benchmark::servers(&settings),
+                &benchmark::clients(&settings),
+            )
+        }
+        Command::Echo {
+            pattern,
+            port,
+            stats_port,
+        } => echo::serve(pattern, port, stats_port).await,
+        Command::Loadgen(loadgen_args) => loadgen::run(loadgen_args).await,
+        Command::Fetch { output } => {
+            prepare_base_dir(args.base_dir, &config)?;
+            offline::fetch(&output)
+        }
+    }
+}
+
+async fn run_benchmark(
+    run_args: RunArgs,
+    base_dir: Option<PathBuf>,
+    offline: Option<PathBuf>,
+    mut config: Config,
+) -> Result<()> {
+    let resumed = match &run_args.resume {
+        Some(dir) => Some(Checkpoint::load(dir)?),
+        None => None,
+    };
+    if resumed.is_none() {
+        if let Some(profile) = &run_args.profile {
+            config::apply_profile(&mut config, profile, run_args.profile_file.as_deref())?;
+            println!("Using profile: {}", profile);
+        }
+    }
+    let mut run = config.run.clone();
+    run_args.apply(&mut run);
+    check_output(&run)?;
+
+    prepare_base_dir(base_dir, &config)?;
+    if let Some(source) = &offline {
+        util::set_mirror(offline::prepare(source)?)?;
+    }
+    echo::set_target_exe(std::env::current_exe()?);
+
+    // a resumed run keeps the settings it was started with, only the output can change
+    let checkpoint = match resumed {
+        Some(checkpoint) => {
+            println!(
+                "Resuming {}, {} pair(s) already completed",
+                checkpoint.dir.display(),
+                checkpoint.file.results.len()
+            );
+            if let Some(stored) = &checkpoint.file.run {
+                run = stored.clone();
+                run_args.apply(&mut run);
+                check_output(&run)?;
+            }
+            checkpoint
+        }
+        None => {
+            let settings = run.settings(server::option_sets(
+                &config.server_options,
+                &run_args.server_options,
+                &run_args.server_sweeps,
+            )?)?;
+            let mut checkpoint = Checkpoint::create(util::get_cpu_info()?, settings)?;
+            checkpoint.file.profile = run_args.profile.clone();
+            checkpoint.file.run = Some(run.clone());
+            checkpoint.save()?;
+            println!("Run directory: {}", checkpoint.dir.display());
+            checkpoint
+        }
+    };
+
+    let dir = checkpoint.dir.clone();
+    let file = BenchmarkRunner::from_checkpoint(checkpoint).run().await?;
+    let output = file.markdown()?;
+    if run.print_md {
+        println!("{}", output);
+    }
+    if run.formats.contains(&OutputFormat::Markdown) {
+        tokio::fs::write(&run.output, output).await?;
+        println!("\nMarkdown results written to: {}", run.output.display());
+    }
+    if run.formats.contains(&OutputFormat::Json) {
+        let json_output = run.output.with_extension("json");
+        file.save(&json_output)?;
+        println!("JSON results written to: {}", json_output.display());
+    }
+    if file.partial {
+        println!("Resume with: wispmark run --resume {}", dir.display());
+    }
+
+    Ok(())
+}
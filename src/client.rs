@@ -1,8 +1,19 @@
-use crate::structure::{EpoxyClient, WispClient, WispNode};
-use crate::util;
-use anyhow::{Context, Result};
-use std::path::PathBuf;
-use std::process::{Child, Command};
+use crate::placement::Role;
+use crate::process::ProcessHandle;
+use crate::structure::{
+    Compression, EpoxyClient, LoadShape, PayloadMode, RunHandle, RunOptions, TrafficPattern,
+    Transport, WispClient, WispNode,
+};
+use crate::{offline, util};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::path::Path;
+
+// client.mjs tops up the send buffer of every stream once it holds fewer than 20 payloads
+const WISP_JS_DEPTH: usize = 20;
+// simple-wisp-client never waits for echoes, so only the server's flow control buffer bounds
+// it, commonly 128 packets
+const WISP_MUX_DEPTH: usize = 128;
 
 impl WispNode {
     pub fn new(streams: usize, instances: usize) -> Self {
@@ -22,26 +33,72 @@ impl WispNode {
     }
 }
 
+#[async_trait]
 impl WispClient for WispNode {
     fn name(&self) -> &str {
         &self.name
     }
 
-    fn install(&self) -> Result<()> {
+    fn install(&self, log_file: &Path) -> Result<()> {
         std::fs::create_dir_all(&self.path)?;
-        Command::new("npm")
-            .arg("i")
-            .current_dir(&self.path)
-            .status()
-            .context("Failed to run npm install")?;
-        Ok(())
+        util::run_logged(
+            util::npm_install().current_dir(&self.path),
+            log_file,
+            "run npm install",
+        )
+    }
+
+    fn fetch(&self, mirror: &Path, log_file: &Path) -> Result<()> {
+        offline::cache_npm(&self.path, mirror, log_file)
     }
 
     fn check_install(&self) -> bool {
-        self.path.join("node_modules").exists()
+        let modules = self.path.join("node_modules");
+        modules.join("@mercuryworkshop/wisp-js").exists() && modules.join("ws").exists()
+    }
+
+    fn requirements(&self) -> Vec<&str> {
+        vec!["node", "npm"]
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn version(&self) -> Option<String> {
+        util::npm_version(&self.path, "@mercuryworkshop/wisp-js")
+    }
+
+    fn update(&self, _reference: Option<&str>, log_file: &Path) -> Result<()> {
+        util::npm_update(&self.path, log_file)
+    }
+
+    fn shape(&self, options: &RunOptions) -> LoadShape {
+        LoadShape {
+            connections: self.instances * self.streams,
+            payload_size: options.payload_size,
+            depth: WISP_JS_DEPTH,
+            pattern: options.pattern,
+        }
     }
 
-    fn run(&self, server_port: u16, target_port: u16, log_file: &PathBuf) -> Result<Vec<Child>> {
+    fn clean(&self) -> Result<()> {
+        // client.mjs and package.json are written by wispmark itself
+        util::remove_dir(&self.path.join("node_modules"))?;
+        let lockfile = self.path.join("package-lock.json");
+        if lockfile.exists() {
+            std::fs::remove_file(lockfile)?;
+        }
+        Ok(())
+    }
+
+    async fn start(
+        &self,
+        server_port: u16,
+        target_port: u16,
+        options: &RunOptions,
+        log_file: &Path,
+    ) -> Result<Box<dyn RunHandle>> {
         let mut children = Vec::with_capacity(self.instances);
 
         for i in 0..self.instances {
@@ -51,16 +108,22 @@ impl WispClient for WispNode {
                 let ext = log_file.extension().unwrap_or_default().to_string_lossy();
                 parent.join(format!("{}_{}.{}", stem, i, ext))
             } else {
-                log_file.clone()
+                log_file.to_path_buf()
             };
 
             let child = util::run(
+                Role::Client,
                 "node",
                 &[
                     "client.mjs",
                     &server_port.to_string(),
                     &target_port.to_string(),
                     &self.streams.to_string(),
+                    &options.payload_size.to_string(),
+                    &options.pattern.to_string(),
+                    &options.transport.to_string(),
+                    &options.compression.to_string(),
+                    &options.payload_mode.to_string(),
                 ],
                 Some(&self.path),
                 &instance_log,
@@ -69,13 +132,13 @@ impl WispClient for WispNode {
             children.push(child);
         }
 
-        Ok(children)
+        Ok(Box::new(ProcessHandle::clients(children)))
     }
 }
 
 impl EpoxyClient {
     pub fn new(streams: usize, instances: usize) -> Self {
-        let path = util::base().join("client/rust");
+        let path = util::epoxy_dir();
         let epoxy_src = path.join("simple-wisp-client");
         let name = if instances == 1 {
             format!("wisp-mux ({})", streams)
@@ -93,34 +156,80 @@ impl EpoxyClient {
     }
 }
 
+#[async_trait]
 impl WispClient for EpoxyClient {
     fn name(&self) -> &str {
         &self.name
     }
 
-    fn install(&self) -> Result<()> {
-        if !self.path.exists() {
-            Command::new("git")
-                .args(&["clone", "https://github.com/MercuryWorkshop/epoxy-tls"])
-                .arg(&self.path)
-                .status()
-                .context("Failed to clone epoxy-tls")?;
-        }
+    fn install(&self, log_file: &Path) -> Result<()> {
+        util::clone(util::EPOXY_REPO, &self.path, log_file)?;
 
-        Command::new("cargo")
-            .args(&["b", "-r"])
-            .current_dir(&self.epoxy_src)
-            .status()
-            .context("Failed to build Rust client")?;
+        util::run_logged(
+            util::cargo(&["b", "-r"]).current_dir(&self.epoxy_src),
+            log_file,
+            "build Rust client",
+        )
+    }
 
-        Ok(())
+    fn fetch(&self, mirror: &Path, log_file: &Path) -> Result<()> {
+        let repo = offline::fetch_repo(util::EPOXY_REPO, mirror, log_file)?;
+        offline::vendor_cargo(&repo, log_file)
     }
 
     fn check_install(&self) -> bool {
         self.path.join("target/release/simple-wisp-client").exists()
     }
 
-    fn run(&self, server_port: u16, target_port: u16, log_file: &PathBuf) -> Result<Vec<Child>> {
+    fn requirements(&self) -> Vec<&str> {
+        vec!["git", "cargo", "rustc", "gcc"]
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn version(&self) -> Option<String> {
+        util::git_version(&self.path)
+    }
+
+    fn update(&self, reference: Option<&str>, log_file: &Path) -> Result<()> {
+        util::git_update(&self.path, reference, log_file)?;
+        self.install(log_file)
+    }
+
+    fn clean(&self) -> Result<()> {
+        util::remove_dir(&self.path)
+    }
+
+    fn shape(&self, options: &RunOptions) -> LoadShape {
+        LoadShape {
+            connections: self.instances * self.streams,
+            payload_size: options.payload_size,
+            depth: WISP_MUX_DEPTH,
+            pattern: options.pattern,
+        }
+    }
+
+    fn supports(&self, options: &RunOptions) -> bool {
+        // simple-wisp-client takes its packet size in KiB, always uploads zeroes,
+        // doesn't negotiate deflate and has no way to trust the self-signed
+        // certificate used for wss
+        options.payload_size >= 1024
+            && options.payload_size.is_multiple_of(1024)
+            && options.pattern != TrafficPattern::Download
+            && options.transport == Transport::Ws
+            && options.compression == Compression::Off
+            && options.payload_mode == PayloadMode::Compressible
+    }
+
+    async fn start(
+        &self,
+        server_port: u16,
+        target_port: u16,
+        options: &RunOptions,
+        log_file: &Path,
+    ) -> Result<Box<dyn RunHandle>> {
         let mut children = Vec::with_capacity(self.instances);
 
         for i in 0..self.instances {
@@ -130,11 +239,12 @@ impl WispClient for EpoxyClient {
                 let ext = log_file.extension().unwrap_or_default().to_string_lossy();
                 parent.join(format!("{}_{}.{}", stem, i, ext))
             } else {
-                log_file.clone()
+                log_file.to_path_buf()
             };
 
             let binary_path = self.path.join("target/release/simple-wisp-client");
             let child = util::run(
+                Role::Client,
                 binary_path.to_str().unwrap(),
                 &[
                     "-w",
@@ -144,7 +254,7 @@ impl WispClient for EpoxyClient {
                     "-s",
                     &self.streams.to_string(),
                     "-p",
-                    "50",
+                    &(options.payload_size / 1024).to_string(),
                 ],
                 Some(&self.epoxy_src),
                 &instance_log,
@@ -153,15 +263,25 @@ impl WispClient for EpoxyClient {
             children.push(child);
         }
 
-        Ok(children)
+        Ok(Box::new(ProcessHandle::clients(children)))
     }
 }
 
 pub fn get_implementations() -> Vec<Box<dyn WispClient>> {
-    vec![
-        Box::new(WispNode::new(10, 1)),
-        Box::new(WispNode::new(10, 5)),
-        Box::new(EpoxyClient::new(10, 1)),
-        Box::new(EpoxyClient::new(10, 5)),
-    ]
+    get_matrix(&[10], &[1, 5])
+}
+
+pub fn get_matrix(streams: &[usize], instances: &[usize]) -> Vec<Box<dyn WispClient>> {
+    let mut clients: Vec<Box<dyn WispClient>> = Vec::new();
+    for &streams in streams {
+        for &instances in instances {
+            clients.push(Box::new(WispNode::new(streams, instances)));
+        }
+    }
+    for &streams in streams {
+        for &instances in instances {
+            clients.push(Box::new(EpoxyClient::new(streams, instances)));
+        }
+    }
+    clients
 }
@@ -0,0 +1,626 @@
+use crate::impair::Impairment;
+use crate::noise;
+use crate::placement::{CpuSet, Placement};
+use crate::server::{self, OptionSets};
+use crate::structure::{
+    BenchmarkSettings, Compression, OutputFormat, PayloadMode, ServerOptions, TrafficPattern,
+    Transport,
+};
+use crate::util;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::error::ErrorKind;
+use clap::{Args, Parser};
+use dirs::config_dir;
+use serde::de::{Error as _, Visitor};
+use serde::forward_to_deserialize_any;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Config {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub server_options: HashMap<String, ServerOptions>,
+    #[serde(flatten)]
+    pub run: RunConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RunConfig {
+    pub duration: u64,
+    pub jobs: usize,
+    pub repeats: usize,
+    pub servers: Vec<String>,
+    pub clients: Vec<String>,
+    pub streams: Vec<usize>,
+    pub instances: Vec<usize>,
+    #[serde(deserialize_with = "deserialize_sizes")]
+    pub payload_sizes: Vec<usize>,
+    pub patterns: Vec<TrafficPattern>,
+    pub transports: Vec<Transport>,
+    pub compression: Vec<Compression>,
+    pub payload_modes: Vec<PayloadMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reorder: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stall_every: Option<u64>,
+    pub stall_for: u64,
+    pub fairness: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_cpus: Option<CpuSet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cpus: Option<CpuSet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo_cpus: Option<CpuSet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_cpu_limit: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_memory_limit: Option<usize>,
+    pub max_noise: f64,
+    pub abort_when_busy: bool,
+    pub baseline: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baseline_depth: Option<usize>,
+    pub output: PathBuf,
+    pub formats: Vec<OutputFormat>,
+    pub print_md: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(usize),
+    Text(String),
+}
+
+// sizes may be written as plain byte counts or with a suffix, like on the command line
+fn deserialize_sizes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<usize>, D::Error> {
+    Vec::<Size>::deserialize(deserializer)?
+        .into_iter()
+        .map(|size| match size {
+            Size::Bytes(bytes) => Ok(bytes),
+            Size::Text(text) => util::parse_size(&text).map_err(D::Error::custom),
+        })
+        .collect()
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            duration: 10,
+            jobs: 4,
+            repeats: 1,
+            servers: Vec::new(),
+            clients: Vec::new(),
+            streams: vec![10],
+            instances: vec![1, 5],
+            payload_sizes: vec![50 * 1024],
+            patterns: vec![TrafficPattern::Echo],
+            transports: vec![Transport::Ws],
+            compression: vec![Compression::Off],
+            payload_modes: vec![PayloadMode::Compressible],
+            latency: None,
+            jitter: None,
+            rate_limit: None,
+            reorder: None,
+            stall_every: None,
+            stall_for: 200,
+            fairness: false,
+            server_cpus: None,
+            client_cpus: None,
+            echo_cpus: None,
+            server_cpu_limit: None,
+            server_memory_limit: None,
+            max_noise: noise::DEFAULT_MAX_NOISE,
+            abort_when_busy: false,
+            baseline: true,
+            baseline_depth: None,
+            output: PathBuf::from("wispmark-results.md"),
+            formats: vec![OutputFormat::Markdown, OutputFormat::Json],
+            print_md: true,
+        }
+    }
+}
+
+impl RunConfig {
+    pub fn settings(&self, server_options: OptionSets) -> Result<BenchmarkSettings> {
+        if self.rate_limit == Some(0) {
+            bail!("rate_limit must be greater than zero");
+        }
+        if self.stall_every == Some(0) || self.stall_every.is_some() && self.stall_for == 0 {
+            bail!("stall_every and stall_for must be greater than zero");
+        }
+        let impaired = self.latency.is_some()
+            || self.jitter.is_some()
+            || self.rate_limit.is_some()
+            || self.reorder.is_some()
+            || self.stall_every.is_some();
+        Ok(BenchmarkSettings {
+            duration: self.duration,
+            jobs: self.jobs,
+            repeats: self.repeats.max(1),
+            servers: self.servers.clone(),
+            clients: self.clients.clone(),
+            streams: self.streams.clone(),
+            instances: self.instances.clone(),
+            payload_sizes: self.payload_sizes.clone(),
+            patterns: self.patterns.clone(),
+            transports: self.transports.clone(),
+            compressions: self.compression.clone(),
+            payload_modes: self.payload_modes.clone(),
+            server_options,
+            impairment: impaired.then(|| Impairment {
+                latency: Duration::from_millis(self.latency.unwrap_or_default()),
+                jitter: Duration::from_millis(self.jitter.unwrap_or_default()),
+                rate: self.rate_limit,
+                reorder: self.reorder.unwrap_or(0.0).clamp(0.0, 1.0),
+                stall_every: self.stall_every.map(Duration::from_millis),
+                stall_for: Duration::from_millis(self.stall_for),
+            }),
+            fairness: self.fairness,
+            placement: Placement {
+                server_cpus: self.server_cpus.clone(),
+                client_cpus: self.client_cpus.clone(),
+                echo_cpus: self.echo_cpus.clone(),
+                server_cpu_limit: self.server_cpu_limit,
+                server_memory_limit: self.server_memory_limit,
+            },
+            max_noise: self.max_noise,
+            abort_when_busy: self.abort_when_busy,
+            baseline: self.baseline,
+            baseline_depth: self.baseline_depth,
+        })
+    }
+}
+
+#[derive(Args, Debug, Default)]
+pub struct RunArgs {
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    #[arg(long)]
+    pub profile_file: Option<PathBuf>,
+
+    #[arg(long, value_name = "RUN_DIR")]
+    pub resume: Option<PathBuf>,
+
+    #[arg(long)]
+    pub duration: Option<u64>,
+
+    #[arg(long)]
+    pub jobs: Option<usize>,
+
+    #[arg(long)]
+    pub repeats: Option<usize>,
+
+    #[arg(long, value_delimiter = ',')]
+    pub servers: Vec<String>,
+
+    #[arg(long, value_delimiter = ',')]
+    pub clients: Vec<String>,
+
+    #[arg(long, value_delimiter = ',')]
+    pub streams: Vec<usize>,
+
+    #[arg(long, value_delimiter = ',')]
+    pub instances: Vec<usize>,
+
+    #[arg(long, value_delimiter = ',', value_parser = util::parse_size)]
+    pub payload_sizes: Vec<usize>,
+
+    #[arg(long, value_delimiter = ',', value_enum)]
+    pub patterns: Vec<TrafficPattern>,
+
+    #[arg(long, value_delimiter = ',', value_enum)]
+    pub transports: Vec<Transport>,
+
+    #[arg(long, value_delimiter = ',', value_enum)]
+    pub compression: Vec<Compression>,
+
+    #[arg(long, value_delimiter = ',', value_enum)]
+    pub payload_modes: Vec<PayloadMode>,
+
+    #[arg(long = "server-option", value_parser = server::parse_option)]
+    pub server_options: Vec<(String, String, String)>,
+
+    #[arg(long = "server-sweep", value_parser = server::parse_option)]
+    pub server_sweeps: Vec<(String, String, String)>,
+
+    #[arg(long, value_parser = util::parse_millis)]
+    pub latency: Option<Duration>,
+
+    #[arg(long, value_parser = util::parse_millis)]
+    pub jitter: Option<Duration>,
+
+    #[arg(long, value_parser = util::parse_size)]
+    pub rate_limit: Option<usize>,
+
+    #[arg(long)]
+    pub reorder: Option<f64>,
+
+    #[arg(long, value_parser = util::parse_millis)]
+    pub stall_every: Option<Duration>,
+
+    #[arg(long, value_parser = util::parse_millis)]
+    pub stall_for: Option<Duration>,
+
+    #[arg(long)]
+    pub fairness: Option<bool>,
+
+    #[arg(long)]
+    pub server_cpus: Option<CpuSet>,
+
+    #[arg(long)]
+    pub client_cpus: Option<CpuSet>,
+
+    #[arg(long)]
+    pub echo_cpus: Option<CpuSet>,
+
+    #[arg(long)]
+    pub server_cpu_limit: Option<f64>,
+
+    #[arg(long, value_parser = util::parse_size)]
+    pub server_memory_limit: Option<usize>,
+
+    #[arg(long)]
+    pub max_noise: Option<f64>,
+
+    #[arg(long)]
+    pub abort_when_busy: Option<bool>,
+
+    #[arg(long)]
+    pub baseline: Option<bool>,
+
+    #[arg(long)]
+    pub baseline_depth: Option<usize>,
+
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+
+    #[arg(long, value_delimiter = ',', value_enum)]
+    pub formats: Vec<OutputFormat>,
+
+    #[arg(long)]
+    pub print_md: Option<bool>,
+}
+
+impl RunArgs {
+    // anything given on the command line replaces the stored default
+    pub fn apply(&self, config: &mut RunConfig) {
+        let millis = |d: &Duration| d.as_millis() as u64;
+        if let Some(duration) = self.duration {
+            config.duration = duration;
+        }
+        if let Some(jobs) = self.jobs {
+            config.jobs = jobs;
+        }
+        if let Some(repeats) = self.repeats {
+            config.repeats = repeats;
+        }
+        if !self.servers.is_empty() {
+            config.servers = self.servers.clone();
+        }
+        if !self.clients.is_empty() {
+            config.clients = self.clients.clone();
+        }
+        if !self.streams.is_empty() {
+            config.streams = self.streams.clone();
+        }
+        if !self.instances.is_empty() {
+            config.instances = self.instances.clone();
+        }
+        if !self.payload_sizes.is_empty() {
+            config.payload_sizes = self.payload_sizes.clone();
+        }
+        if !self.patterns.is_empty() {
+            config.patterns = self.patterns.clone();
+        }
+        if !self.transports.is_empty() {
+            config.transports = self.transports.clone();
+        }
+        if !self.compression.is_empty() {
+            config.compression = self.compression.clone();
+        }
+        if !self.payload_modes.is_empty() {
+            config.payload_modes = self.payload_modes.clone();
+        }
+        if let Some(latency) = &self.latency {
+            config.latency = Some(millis(latency));
+        }
+        if let Some(jitter) = &self.jitter {
+            config.jitter = Some(millis(jitter));
+        }
+        if let Some(rate) = self.rate_limit {
+            config.rate_limit = Some(rate);
+        }
+        if let Some(reorder) = self.reorder {
+            config.reorder = Some(reorder);
+        }
+        if let Some(every) = &self.stall_every {
+            config.stall_every = Some(millis(every));
+        }
+        if let Some(stall_for) = &self.stall_for {
+            config.stall_for = millis(stall_for);
+        }
+        if let Some(fairness) = self.fairness {
+            config.fairness = fairness;
+        }
+        if let Some(cpus) = &self.server_cpus {
+            config.server_cpus = Some(cpus.clone());
+        }
+        if let Some(cpus) = &self.client_cpus {
+            config.client_cpus = Some(cpus.clone());
+        }
+        if let Some(cpus) = &self.echo_cpus {
+            config.echo_cpus = Some(cpus.clone());
+        }
+        if let Some(limit) = self.server_cpu_limit {
+            config.server_cpu_limit = Some(limit);
+        }
+        if let Some(limit) = self.server_memory_limit {
+            config.server_memory_limit = Some(limit);
+        }
+        if let Some(max_noise) = self.max_noise {
+            config.max_noise = max_noise;
+        }
+        if let Some(abort) = self.abort_when_busy {
+            config.abort_when_busy = abort;
+        }
+        if let Some(baseline) = self.baseline {
+            config.baseline = baseline;
+        }
+        if let Some(depth) = self.baseline_depth {
+            config.baseline_depth = Some(depth);
+        }
+        if let Some(output) = &self.output {
+            config.output = output.clone();
+        }
+        if !self.formats.is_empty() {
+            config.formats = self.formats.clone();
+        }
+        if let Some(print_md) = self.print_md {
+            config.print_md = print_md;
+        }
+    }
+}
+
+#[derive(Parser)]
+#[command(name = "wispmark config set")]
+struct SingleOption {
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+pub fn path() -> PathBuf {
+    config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("wispmark")
+        .join("config.json")
+}
+
+pub fn load() -> Result<Config> {
+    let path = path();
+
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+
+    let contents = std::fs::read_to_string(&path).context("Failed to read config")?;
+
+    serde_json::from_str(&contents).context("Failed to parse config")
+}
+
+pub fn save(config: &Config) -> Result<()> {
+    let config_path = path();
+    if let Some(parent) = config_path.parent() {
+        std::fs::create_dir_all(parent).context("Failed to create config directory")?;
+    }
+
+    let json = serde_json::to_string_pretty(config).context("Failed to prettify config")?;
+
+    std::fs::write(&config_path, json).context("Failed to write config file")
+}
+
+// the keys of a run config are the field names its derived Deserialize asks for, so they can't
+// drift from the struct
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> Deserializer<'de> for FieldNames<'_> {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(Self::Error::custom("only structs have field names"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(Self::Error::custom("field names collected"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+fn is_key(key: &str) -> bool {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = RunConfig::deserialize(FieldNames(&mut fields));
+    key == "base_dir" || key == "server_options" || fields.contains(&key)
+}
+
+#[derive(Deserialize, Default)]
+struct ProfileFile {
+    #[serde(default)]
+    profile: BTreeMap<String, toml::Table>,
+}
+
+pub fn profile_path() -> PathBuf {
+    path().with_file_name("profiles.toml")
+}
+
+// profile values sit between the stored defaults and the command line
+pub fn apply_profile(config: &mut Config, name: &str, file: Option<&Path>) -> Result<()> {
+    let path = file.map(Path::to_path_buf).unwrap_or_else(profile_path);
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read profiles from {}", path.display()))?;
+    let profiles: ProfileFile = toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let Some(profile) = profiles.profile.get(name) else {
+        let names: Vec<&str> = profiles.profile.keys().map(String::as_str).collect();
+        bail!(
+            "No profile named {} in {}, available: {}",
+            name,
+            path.display(),
+            names.join(", ")
+        );
+    };
+
+    let mut run = serde_json::to_value(&config.run)?;
+    for (key, value) in profile {
+        let value = serde_json::to_value(value)?;
+        match key.as_str() {
+            "server_options" => {
+                let options: HashMap<String, ServerOptions> = serde_json::from_value(value)
+                    .with_context(|| format!("Invalid server_options in profile {}", name))?;
+                for (kind, options) in options {
+                    config.server_options.entry(kind).or_default().extend(options);
+                }
+            }
+            "base_dir" => bail!("base_dir can't be set from a profile"),
+            key if is_key(key) => run[key] = value,
+            _ => bail!("Unknown key {} in profile {}", key, name),
+        }
+    }
+    config.run =
+        serde_json::from_value(run).with_context(|| format!("Invalid profile {}", name))?;
+    Ok(())
+}
+
+fn field(key: &str) -> String {
+    key.replace('-', "_")
+}
+
+pub fn get(key: Option<&str>) -> Result<String> {
+    let config = serde_json::to_value(load()?)?;
+    let value = match key {
+        None => &config,
+        Some(key) if is_key(&field(key)) => {
+            config.get(field(key)).unwrap_or(&Value::Null)
+        }
+        Some(key) => bail!("Unknown config key {}", key),
+    };
+    Ok(serde_json::to_string_pretty(value)?)
+}
+
+pub fn set(key: &str, value: &str) -> Result<()> {
+    let mut config = load()?;
+
+    match field(key).as_str() {
+        "base_dir" => {
+            let dir = PathBuf::from(value);
+            std::fs::create_dir_all(&dir).context("Failed to create base directory")?;
+            config.base_dir = Some(
+                dir.canonicalize()
+                    .context("Failed to extend base directory")?,
+            );
+        }
+        "server_option" => {
+            let option = server::parse_option(value).map_err(|e| anyhow!(e))?;
+            server::option_sets(&HashMap::new(), std::slice::from_ref(&option), &[])?;
+            let (kind, key, value) = option;
+            config
+                .server_options
+                .entry(kind)
+                .or_default()
+                .insert(key, server::parse_value(&value));
+        }
+        "server_sweep" => bail!("Sweeps only apply to a single run and can't be stored"),
+        "profile" | "profile_file" => bail!("Profiles are selected per run with --profile"),
+        "resume" => bail!("Runs are resumed with --resume"),
+        _ => {
+            let flag = format!("--{}", key.replace('_', "-"));
+            let parsed = match SingleOption::try_parse_from(["wispmark", &flag, value]) {
+                Ok(parsed) => parsed,
+                Err(e) if e.kind() == ErrorKind::UnknownArgument => {
+                    bail!("Unknown config key {}", key)
+                }
+                Err(e) => {
+                    // the value parser's own message, or the possible values clap lists
+                    let message = match std::error::Error::source(&e) {
+                        Some(source) => source.to_string(),
+                        None => e
+                            .render()
+                            .to_string()
+                            .lines()
+                            .map(str::trim)
+                            .find(|line| line.starts_with("[possible values"))
+                            .unwrap_or("not accepted")
+                            .trim_matches(['[', ']'])
+                            .to_string(),
+                    };
+                    bail!("Invalid value {} for {}: {}", value, key, message)
+                }
+            };
+            parsed.run.apply(&mut config.run);
+        }
+    }
+
+    save(&config)?;
+    println!("Saved {} to {}", key, path().display());
+    Ok(())
+}
+
+pub fn unset(key: &str) -> Result<()> {
+    let mut config = load()?;
+
+    match field(key).as_str() {
+        "base_dir" => config.base_dir = None,
+        "server_option" => bail!("Use `config unset server_options` to clear server options"),
+        field => {
+            if !is_key(field) {
+                bail!("Unknown config key {}", key);
+            }
+            let mut value = serde_json::to_value(&config)?;
+            value.as_object_mut().unwrap().remove(field);
+            config = serde_json::from_value(value).context("Failed to reset config key")?;
+        }
+    }
+
+    save(&config)?;
+    println!("Reset {} in {}", key, path().display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_follow_the_config() {
+        let config = serde_json::to_value(Config::default()).unwrap();
+        for key in config.as_object().unwrap().keys() {
+            assert!(is_key(key), "{} is not a config key", key);
+        }
+        assert!(is_key("base_dir"));
+        assert!(is_key("baseline_depth"));
+        assert!(!is_key("resume"));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::structure::{WispClient, WispServer};
+use crate::{benchmark, system, util};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+const MIN_FREE_SPACE: u64 = 4 * 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Ok => write!(f, "ok"),
+            Status::Warn => write!(f, "warn"),
+            Status::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+struct Check {
+    name: String,
+    status: Status,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+pub fn run(servers: &[Box<dyn WispServer>], clients: &[Box<dyn WispClient>]) -> Result<()> {
+    let mut checks = Vec::new();
+
+    for (tool, users) in requirements(servers, clients) {
+        checks.push(check_tool(&tool, &users));
+    }
+    checks.push(check_sudo());
+    for (port, what) in [
+        (util::WISP_PORT, "Wisp server"),
+        (util::ECHO_PORT, "echo target"),
+        (util::RELAY_PORT, "impairment relay"),
+        (util::TLS_PORT, "TLS proxy"),
+        (util::STATS_PORT, "stream stats"),
+        (util::FAULT_PORT, "fault target"),
+        (util::CLOSED_PORT, "closed target port"),
+    ] {
+        checks.push(check_port(port, what));
+    }
+    checks.push(check_governor());
+    checks.push(check_disk(&util::base()));
+
+    let mut table = vec![vec![
+        "Check".to_string(),
+        "Status".to_string(),
+        "Details".to_string(),
+    ]];
+    for check in &checks {
+        table.push(vec![
+            check.name.clone(),
+            check.status.to_string(),
+            check.detail.clone(),
+        ]);
+    }
+    println!("{}", benchmark::format_table(&table));
+
+    let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+    if failed > 0 {
+        bail!("{} required check(s) failed", failed);
+    }
+    println!("Ready to benchmark.");
+    Ok(())
+}
+
+fn requirements(
+    servers: &[Box<dyn WispServer>],
+    clients: &[Box<dyn WispClient>],
+) -> BTreeMap<String, Vec<String>> {
+    let mut tools: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    // the echo target and bandwidth measurement are needed by every run
+    for tool in ["git", "cargo"] {
+        tools.entry(tool.to_string()).or_default().push("echo".to_string());
+    }
+    for tool in ["iftop", "netstat"] {
+        tools.entry(tool.to_string()).or_default().push("wispmark".to_string());
+    }
+
+    let names = servers
+        .iter()
+        .map(|s| (s.name(), s.requirements()))
+        .chain(clients.iter().map(|c| (c.name(), c.requirements())));
+    for (name, required) in names {
+        for tool in required {
+            let users = tools.entry(tool.to_string()).or_default();
+            if !users.iter().any(|u| u == name) {
+                users.push(name.to_string());
+            }
+        }
+    }
+    tools
+}
+
+fn find_in_path(tool: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(tool))
+        .find(|candidate| candidate.is_file())
+}
+
+fn check_tool(tool: &str, users: &[String]) -> Check {
+    let needed_by = format!("needed by {}", users.join(", "));
+    let Some(path) = find_in_path(tool) else {
+        return Check::new(tool, Status::Fail, format!("not found, {}", needed_by));
+    };
+    let version = system::tool_version(tool).unwrap_or_else(|| path.display().to_string());
+    if tool == "rustc" && !version.contains("nightly") {
+        return Check::new(
+            tool,
+            Status::Warn,
+            format!("{}, nightly expected by epoxy-tls", version),
+        );
+    }
+    Check::new(tool, Status::Ok, version)
+}
+
+fn check_sudo() -> Check {
+    if find_in_path("sudo").is_none() {
+        return Check::new("sudo", Status::Fail, "not found, needed to run iftop");
+    }
+    let passwordless = Command::new("sudo")
+        .args(["-n", "true"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success());
+    if passwordless {
+        Check::new("sudo", Status::Ok, "available for iftop")
+    } else {
+        Check::new("sudo", Status::Warn, "will prompt for a password")
+    }
+}
+
+fn check_port(port: u16, what: &str) -> Check {
+    let name = format!("port {}", port);
+    match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(_) => Check::new(name, Status::Ok, format!("free for the {}", what)),
+        Err(e) => Check::new(name, Status::Fail, format!("unavailable for the {}: {}", what, e)),
+    }
+}
+
+fn check_governor() -> Check {
+    let governors = system::governors();
+    if governors.is_empty() {
+        Check::new("cpu governor", Status::Ok, "not exposed")
+    } else if governors.iter().all(|g| g == "performance") {
+        Check::new("cpu governor", Status::Ok, "performance")
+    } else {
+        Check::new(
+            "cpu governor",
+            Status::Warn,
+            format!("{}, results may be noisy", governors.join(", ")),
+        )
+    }
+}
+
+fn check_disk(dir: &Path) -> Check {
+    let name = "disk space";
+    let available = Command::new("df")
+        .arg("-Pk")
+        .arg(dir)
+        .output()
+        .ok()
+        .and_then(|output| {
+            let text = String::from_utf8_lossy(&output.stdout).to_string();
+            let line = text.lines().nth(1)?.to_string();
+            line.split_whitespace().nth(3)?.parse::<u64>().ok()
+        })
+        .map(|kib| kib * 1024);
+
+    match available {
+        Some(bytes) if bytes < MIN_FREE_SPACE => Check::new(
+            name,
+            Status::Fail,
+            format!(
+                "{:.1} GiB free in {}, at least {:.0} GiB needed",
+                bytes as f64 / GIB,
+                dir.display(),
+                MIN_FREE_SPACE as f64 / GIB
+            ),
+        ),
+        Some(bytes) => Check::new(
+            name,
+            Status::Ok,
+            format!("{:.1} GiB free in {}", bytes as f64 / GIB, dir.display()),
+        ),
+        None => Check::new(name, Status::Warn, format!("could not check {}", dir.display())),
+    }
+}
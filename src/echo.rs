@@ -1,6 +1,48 @@
-use crate::util;
+use crate::placement::{self, Role};
+use crate::structure::TrafficPattern;
+use crate::{offline, util};
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const SOURCE_CHUNK: usize = 64 * 1024;
+const TOKIO_REPO: &str = "https://github.com/tokio-rs/tokio";
+
+static TARGET_EXE: OnceLock<PathBuf> = OnceLock::new();
+
+// a running benchmark target or load generator, stopped when dropped
+pub enum Target {
+    Process(Child),
+    Task(JoinHandle<Result<()>>),
+}
+
+impl Drop for Target {
+    fn drop(&mut self) {
+        match self {
+            Target::Process(child) => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            Target::Task(task) => task.abort(),
+        }
+    }
+}
+
+// the CLI serves targets and generates baseline load from separate wispmark processes,
+// embedders do both in-process
+pub fn set_target_exe(exe: PathBuf) {
+    let _ = TARGET_EXE.set(exe);
+}
+
+pub fn target_exe() -> Option<&'static Path> {
+    TARGET_EXE.get().map(PathBuf::as_path)
+}
 
 pub async fn get_echo() -> Result<()> {
     let echo_dir = util::base().join("echo");
@@ -8,38 +50,199 @@ pub async fn get_echo() -> Result<()> {
 
     tokio::fs::create_dir_all(&echo_dir).await?;
 
-    if !echo_repo.exists() {
+    if !echo_repo.join("target/release/examples/echo-tcp").exists() {
         println!("Installing echo...");
-        Command::new("git")
-            .args(&["clone", "https://github.com/tokio-rs/tokio"])
-            .arg(&echo_repo)
-            .status()
-            .context("Failed to clone tokio")?;
+        tokio::fs::create_dir_all(util::log_dir()).await?;
+        let log_file = util::log_dir().join("INSTALL_echo.log");
+        util::clone(TOKIO_REPO, &echo_repo, &log_file)?;
 
-        Command::new("cargo")
-            .args(&["build", "--release", "--example", "echo-tcp"])
-            .current_dir(&echo_repo)
-            .status()
-            .context("Failed to build echo")?;
+        util::run_logged(
+            util::cargo(&["build", "--release", "--example", "echo-tcp"]).current_dir(&echo_repo),
+            &log_file,
+            "build echo",
+        )?;
     }
 
     Ok(())
 }
 
+pub fn fetch(mirror: &Path, log_file: &Path) -> Result<()> {
+    let repo = offline::fetch_repo(TOKIO_REPO, mirror, log_file)?;
+    offline::vendor_cargo(&repo, log_file)
+}
+
 pub fn run_echo() -> Result<Child> {
     let echo_repo = util::base().join("echo/tokio");
 
-    Command::new("cargo")
-        .args(&[
-            "run",
-            "--release",
-            "--example",
-            "echo-tcp",
-            &format!("127.0.0.1:{}", util::ECHO_PORT),
-        ])
+    let address = format!("127.0.0.1:{}", util::ECHO_PORT);
+    let mut command = util::cargo(&["run", "--release", "--example", "echo-tcp", &address]);
+    command
         .current_dir(&echo_repo)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null());
+    placement::apply(Role::Echo, &mut command);
+    command.spawn().context("Failed to run echo")
+}
+
+// with fairness measured, every pattern uses the instrumented target instead of echo-tcp
+pub fn run_target(pattern: TrafficPattern, fairness: bool) -> Result<Target> {
+    if pattern == TrafficPattern::Echo && !fairness {
+        return run_echo().map(Target::Process);
+    }
+
+    let stats_port = fairness.then_some(util::STATS_PORT);
+    let Some(exe) = TARGET_EXE.get() else {
+        return Ok(Target::Task(tokio::spawn(serve(
+            pattern,
+            util::ECHO_PORT,
+            stats_port,
+        ))));
+    };
+    let mut command = Command::new(exe);
+    command.args(["echo", "--pattern"]).arg(pattern.to_string());
+    if let Some(port) = stats_port {
+        command.arg("--stats-port").arg(port.to_string());
+    }
+    placement::apply(Role::Echo, &mut command);
+    command
         .stdout(std::process::Stdio::null())
         .stderr(std::process::Stdio::null())
         .spawn()
-        .context("Failed to run echo")
+        .map(Target::Process)
+        .with_context(|| format!("Failed to run {} target", pattern))
+}
+
+// bytes moved over one target connection, i.e. one Wisp stream
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StreamBytes {
+    pub id: u64,
+    pub up: u64,
+    pub down: u64,
+    pub open: bool,
+}
+
+struct Counter {
+    id: u64,
+    up: AtomicU64,
+    down: AtomicU64,
+    closed: AtomicBool,
+}
+
+type Counters = Arc<Mutex<Vec<Arc<Counter>>>>;
+
+pub async fn serve(pattern: TrafficPattern, port: u16, stats_port: Option<u16>) -> Result<()> {
+    let counters = Counters::default();
+    // bound first, so the stats are reachable as soon as the target is
+    if let Some(stats_port) = stats_port {
+        let stats = TcpListener::bind(("127.0.0.1", stats_port))
+            .await
+            .context("Failed to bind stats port")?;
+        tokio::spawn(serve_stats(stats, counters.clone()));
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .with_context(|| format!("Failed to bind {} target", pattern))?;
+
+    let mut next_id = 0;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let _ = stream.set_nodelay(true);
+        let counter = Arc::new(Counter {
+            id: next_id,
+            up: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        });
+        next_id += 1;
+        counters.lock().unwrap().push(counter.clone());
+        tokio::spawn(async move {
+            let _ = match pattern {
+                TrafficPattern::Echo => echo(stream, &counter).await,
+                TrafficPattern::Upload => discard(stream, &counter).await,
+                TrafficPattern::Download => source(stream, counter.clone()).await,
+            };
+            counter.closed.store(true, Ordering::Relaxed);
+        });
+    }
+}
+
+// every connection gets a snapshot of all streams in accept order, streams reported as
+// closed are dropped afterwards so earlier pairs don't pile up
+async fn serve_stats(listener: TcpListener, counters: Counters) -> Result<()> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let snapshot: Vec<StreamBytes> = {
+            let mut counters = counters.lock().unwrap();
+            let snapshot = counters
+                .iter()
+                .map(|c| StreamBytes {
+                    id: c.id,
+                    up: c.up.load(Ordering::Relaxed),
+                    down: c.down.load(Ordering::Relaxed),
+                    open: !c.closed.load(Ordering::Relaxed),
+                })
+                .collect();
+            counters.retain(|c| !c.closed.load(Ordering::Relaxed));
+            snapshot
+        };
+        let json = serde_json::to_vec(&snapshot)?;
+        tokio::spawn(async move {
+            let _ = stream.write_all(&json).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+pub async fn stream_bytes(stats_port: u16) -> Result<Vec<StreamBytes>> {
+    let mut stream = TcpStream::connect(("127.0.0.1", stats_port))
+        .await
+        .context("Failed to connect to the target stats")?;
+    let mut json = Vec::new();
+    stream.read_to_end(&mut json).await?;
+    serde_json::from_slice(&json).context("Invalid target stats")
+}
+
+async fn echo(mut stream: TcpStream, counter: &Counter) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; SOURCE_CHUNK];
+    loop {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        counter.up.fetch_add(n as u64, Ordering::Relaxed);
+        stream.write_all(&buffer[..n]).await?;
+        counter.down.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+async fn discard(mut stream: TcpStream, counter: &Counter) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; SOURCE_CHUNK];
+    loop {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        counter.up.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+async fn source(stream: TcpStream, counter: Arc<Counter>) -> std::io::Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    // clients that can't stay silent still get their uploads drained
+    let drained = counter.clone();
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; SOURCE_CHUNK];
+        while let Ok(n @ 1..) = reader.read(&mut buffer).await {
+            drained.up.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    });
+
+    let buffer = vec![0u8; SOURCE_CHUNK];
+    loop {
+        writer.write_all(&buffer).await?;
+        counter
+            .down
+            .fetch_add(SOURCE_CHUNK as u64, Ordering::Relaxed);
+    }
 }
@@ -0,0 +1,78 @@
+use crate::echo::StreamBytes;
+use crate::structure::TrafficPattern;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How evenly the throughput of a pair was spread over its streams.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Fairness {
+    pub streams: usize,
+    /// Jain's fairness index, 1.0 when every stream got the same throughput.
+    pub jain: f64,
+    /// Slowest stream in MiB/s.
+    pub min: f64,
+    /// Fastest stream in MiB/s.
+    pub max: f64,
+    /// Streams that moved no data at all.
+    pub stalled: usize,
+}
+
+pub fn jain(values: &[f64]) -> f64 {
+    let sum: f64 = values.iter().sum();
+    let squares: f64 = values.iter().map(|v| v * v).sum();
+    if squares == 0.0 {
+        return 0.0;
+    }
+    sum * sum / (values.len() as f64 * squares)
+}
+
+impl Fairness {
+    // streams that were already closed before the measurement belong to an earlier pair
+    pub fn measure(
+        before: &[StreamBytes],
+        after: &[StreamBytes],
+        pattern: TrafficPattern,
+        elapsed: Duration,
+    ) -> Option<Self> {
+        let bytes = |s: &StreamBytes| match pattern {
+            TrafficPattern::Download => s.down,
+            TrafficPattern::Echo | TrafficPattern::Upload => s.up,
+        };
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let mib = 1024.0 * 1024.0;
+
+        let throughput: Vec<f64> = after
+            .iter()
+            .map(|stream| (stream, before.iter().find(|s| s.id == stream.id)))
+            .filter(|(_, start)| start.is_none_or(|s| s.open))
+            .map(|(stream, start)| {
+                let start = start.map_or(0, bytes);
+                bytes(stream).saturating_sub(start) as f64 / seconds / mib
+            })
+            .collect();
+        if throughput.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            streams: throughput.len(),
+            jain: jain(&throughput),
+            min: throughput.iter().copied().fold(f64::INFINITY, f64::min),
+            max: throughput.iter().copied().fold(0.0, f64::max),
+            stalled: throughput.iter().filter(|&&t| t == 0.0).count(),
+        })
+    }
+
+    // repeated runs are averaged, keeping the worst stall count
+    pub fn combine(runs: &[Fairness]) -> Option<Self> {
+        let n = runs.len() as f64;
+        let average = |f: fn(&Fairness) -> f64| runs.iter().map(f).sum::<f64>() / n;
+        (!runs.is_empty()).then(|| Self {
+            streams: runs.iter().map(|r| r.streams).max().unwrap_or_default(),
+            jain: average(|r| r.jain),
+            min: average(|r| r.min),
+            max: average(|r| r.max),
+            stalled: runs.iter().map(|r| r.stalled).max().unwrap_or_default(),
+        })
+    }
+}
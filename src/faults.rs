@@ -0,0 +1,623 @@
+use crate::config::Config;
+use crate::structure::{
+    Bandwidth, RunHandle, RunOptions, TrafficPattern, Transport, WispClient, WispServer,
+};
+use crate::system::SystemInfo;
+use crate::{benchmark, client, echo, manage, placement, server, util};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::error::Elapsed;
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+const CONNECT: u8 = 0x01;
+const DATA: u8 = 0x02;
+const CONTINUE: u8 = 0x03;
+const CLOSE: u8 = 0x04;
+const STREAM_TCP: u8 = 0x01;
+
+const CLOSE_VOLUNTARY: u8 = 0x02;
+const CLOSE_NETWORK_ERROR: u8 = 0x03;
+const CLOSE_INVALID: u8 = 0x41;
+const CLOSE_REFUSED: u8 = 0x44;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Args, Debug)]
+pub struct FaultArgs {
+    #[arg(long, value_delimiter = ',')]
+    pub servers: Vec<String>,
+
+    #[arg(long, default_value = "wisp-js")]
+    pub client: String,
+
+    #[arg(long, default_value_t = 10)]
+    pub streams: usize,
+
+    #[arg(long)]
+    pub duration: Option<u64>,
+
+    #[arg(long, default_value_t = 0.8)]
+    pub recovery: f64,
+
+    #[arg(long, default_value = "wispmark-faults.md")]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Warn => write!(f, "warning"),
+            Outcome::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaultReport {
+    pub server: String,
+    pub checks: Vec<Check>,
+    pub baseline: Option<Bandwidth>,
+    pub recovered: Option<Bandwidth>,
+}
+
+impl FaultReport {
+    fn count(&self, outcome: Outcome) -> usize {
+        self.checks.iter().filter(|c| c.outcome == outcome).count()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FaultResults {
+    pub system: SystemInfo,
+    pub client: String,
+    pub duration: u64,
+    pub partial: bool,
+    pub reports: Vec<FaultReport>,
+}
+
+fn reason_name(reason: u8) -> String {
+    let name = match reason {
+        0x01 => "unspecified",
+        0x02 => "voluntary",
+        0x03 => "network error",
+        0x04 => "incompatible extensions",
+        0x41 => "invalid",
+        0x42 => "unreachable",
+        0x43 => "timed out",
+        0x44 => "refused",
+        0x47 => "TCP timeout",
+        0x48 => "blocked",
+        0x49 => "throttled",
+        0x81 => "client error",
+        _ => "unknown",
+    };
+    format!("{:#04x} ({})", reason, name)
+}
+
+fn packet(kind: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    packet.extend_from_slice(&stream.to_le_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn connect(stream: u32, kind: u8, port: u16) -> Vec<u8> {
+    let mut payload = vec![kind];
+    payload.extend_from_slice(&port.to_le_bytes());
+    payload.extend_from_slice(b"127.0.0.1");
+    packet(CONNECT, stream, &payload)
+}
+
+struct Packet {
+    kind: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+// a bare Wisp v1 connection that sends whatever bytes it is given
+struct Conn {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl Conn {
+    async fn open() -> Result<Self> {
+        let url = format!("ws://127.0.0.1:{}/", util::WISP_PORT);
+        let (ws, _) = timeout(REPLY_TIMEOUT, tokio_tungstenite::connect_async(url))
+            .await
+            .map_err(|_| anyhow!("WebSocket handshake timed out"))?
+            .context("WebSocket handshake failed")?;
+        Ok(Self { ws })
+    }
+
+    async fn send(&mut self, bytes: Vec<u8>) -> Result<()> {
+        self.ws
+            .send(Message::binary(bytes))
+            .await
+            .context("Failed to send")
+    }
+
+    // None once the server closed the WebSocket
+    async fn next(&mut self) -> Result<Option<Packet>> {
+        loop {
+            let message = timeout(REPLY_TIMEOUT, self.ws.next())
+                .await
+                .with_context(|| format!("No reply within {}s", REPLY_TIMEOUT.as_secs()))?;
+            let bytes = match message {
+                Some(Ok(Message::Binary(bytes))) => bytes,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ok(None),
+                Some(Ok(_)) => continue,
+            };
+            if bytes.len() < 5 {
+                bail!("Server sent a {} byte packet", bytes.len());
+            }
+            return Ok(Some(Packet {
+                kind: bytes[0],
+                stream: u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+                payload: bytes[5..].to_vec(),
+            }));
+        }
+    }
+
+    async fn close_reason(&mut self, stream: u32) -> Result<Option<u8>> {
+        while let Some(packet) = self.next().await? {
+            if packet.kind == CLOSE && packet.stream == stream {
+                return Ok(packet.payload.first().copied());
+            }
+        }
+        Ok(None)
+    }
+
+    async fn expect_close(&mut self, stream: u32, expected: u8) -> Result<(Outcome, String)> {
+        Ok(match self.close_reason(stream).await? {
+            Some(reason) if reason == expected => {
+                (Outcome::Pass, format!("CLOSE {}", reason_name(reason)))
+            }
+            Some(reason) => (
+                Outcome::Warn,
+                format!(
+                    "CLOSE {}, expected {}",
+                    reason_name(reason),
+                    reason_name(expected)
+                ),
+            ),
+            None => (
+                Outcome::Warn,
+                format!(
+                    "Connection closed instead of CLOSE {}",
+                    reason_name(expected)
+                ),
+            ),
+        })
+    }
+
+    // how the server reacted to something it should ignore or reject
+    async fn reaction(&mut self) -> Result<(Outcome, String)> {
+        loop {
+            let packet = match self.next().await {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Ok((Outcome::Pass, "Connection closed".to_string())),
+                Err(e) if e.is::<Elapsed>() => return Ok((Outcome::Pass, "Ignored".to_string())),
+                Err(e) => return Ok((Outcome::Fail, format!("{:#}", e))),
+            };
+            match packet.kind {
+                CLOSE => {
+                    let reason = packet.payload.first().copied().unwrap_or_default();
+                    return Ok((Outcome::Pass, format!("CLOSE {}", reason_name(reason))));
+                }
+                CONTINUE => {}
+                kind => bail!("Server replied with packet type {:#04x}", kind),
+            }
+        }
+    }
+}
+
+// opens a stream to the echo target and waits for the data to come back
+async fn healthy() -> Result<()> {
+    let mut conn = Conn::open().await?;
+    conn.send(connect(1, STREAM_TCP, util::ECHO_PORT)).await?;
+    conn.send(packet(DATA, 1, b"wispmark")).await?;
+    while let Some(packet) = conn.next().await? {
+        match packet.kind {
+            DATA if packet.stream == 1 => return Ok(()),
+            CLOSE if packet.stream == 1 => bail!(
+                "Echo stream closed with {}",
+                reason_name(packet.payload.first().copied().unwrap_or_default())
+            ),
+            _ => {}
+        }
+    }
+    bail!("Connection closed")
+}
+
+// resets the connection when the first byte is 'r' and closes it normally otherwise
+async fn fault_target(listener: TcpListener) {
+    while let Ok((mut stream, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            let mut first = [0u8; 1];
+            if stream.read_exact(&mut first).await.is_ok() && first[0] == b'r' {
+                let _ = socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO));
+            }
+        });
+    }
+}
+
+async fn check(name: &str, fault: impl Future<Output = Result<(Outcome, String)>>) -> Check {
+    let (mut outcome, mut detail) = match fault.await {
+        Ok(result) => result,
+        Err(e) => (Outcome::Fail, format!("{:#}", e)),
+    };
+    if let Err(e) = healthy().await {
+        outcome = Outcome::Fail;
+        detail = format!("{}; server stopped responding: {:#}", detail, e);
+    }
+    println!("{}: {} ({})", name, outcome, detail);
+    Check {
+        name: name.to_string(),
+        outcome,
+        detail,
+    }
+}
+
+struct Faults<'a> {
+    client: &'a dyn WispClient,
+    options: RunOptions,
+    duration: u64,
+    recovery: f64,
+    log_dir: &'a Path,
+}
+
+impl Faults<'_> {
+    async fn measure(&self, name: &str, log: &Path) -> Result<Bandwidth> {
+        self.client.prepare(&self.options).await?;
+        let mut clients = self
+            .client
+            .start(util::WISP_PORT, util::ECHO_PORT, &self.options, log)
+            .await?;
+        if let Err(e) = clients.ready().await {
+            benchmark::stop(clients, "clients").await;
+            return Err(e);
+        }
+        sleep(Duration::from_secs(1)).await;
+        println!("Recording {} speeds for {}s...", name, self.duration);
+        let bandwidth = util::get_bandwidth(util::ECHO_PORT, self.duration).await;
+        // SIGKILL, so the server sees its client vanish mid-stream
+        benchmark::stop(clients, "clients").await;
+        bandwidth
+    }
+
+    async fn run(&self, server: &dyn WispServer, report: &mut FaultReport) {
+        let name = util::log_name(server.name());
+        let server_log = self.log_dir.join(format!("FAULTS_SERVER_{}.log", name));
+        let client_log = self.log_dir.join(format!("FAULTS_CLIENT_{}.log", name));
+
+        if let Err(e) = util::kill(util::WISP_PORT) {
+            eprintln!("Warning: Failed to stop existing server: {}", e);
+        }
+        sleep(Duration::from_secs(1)).await;
+        let started = async {
+            server.prepare(util::WISP_PORT, &self.options).await?;
+            let mut handle = server
+                .start(util::WISP_PORT, &self.options, &server_log)
+                .await?;
+            if let Err(e) = handle.ready().await {
+                benchmark::stop(handle, "server").await;
+                return Err(e);
+            }
+            Ok::<Box<dyn RunHandle>, anyhow::Error>(handle)
+        };
+        let handle = match started.await {
+            Ok(handle) => handle,
+            Err(e) => {
+                report.checks.push(Check {
+                    name: "Server starts".to_string(),
+                    outcome: Outcome::Fail,
+                    detail: format!("{:#}", e),
+                });
+                return;
+            }
+        };
+
+        let baseline = self.measure("baseline", &client_log).await;
+        report.baseline = baseline.as_ref().ok().copied();
+        report.checks.push(
+            check("Client killed mid-stream", async {
+                baseline.map(|b| (Outcome::Pass, format!("Killed after {}", b)))
+            })
+            .await,
+        );
+        report.checks.push(
+            check("Client drops the connection mid-stream", async {
+                let mut conn = Conn::open().await?;
+                for stream in 1..=10 {
+                    conn.send(connect(stream, STREAM_TCP, util::ECHO_PORT))
+                        .await?;
+                    conn.send(packet(DATA, stream, &[0; 4096])).await?;
+                }
+                drop(conn);
+                Ok((Outcome::Pass, "Dropped 10 open streams".to_string()))
+            })
+            .await,
+        );
+        report.checks.push(
+            check("Target port is closed", async {
+                let mut conn = Conn::open().await?;
+                conn.send(connect(1, STREAM_TCP, util::CLOSED_PORT)).await?;
+                conn.expect_close(1, CLOSE_REFUSED).await
+            })
+            .await,
+        );
+        report.checks.push(
+            check("Target resets the connection", async {
+                let mut conn = Conn::open().await?;
+                conn.send(connect(1, STREAM_TCP, util::FAULT_PORT)).await?;
+                conn.send(packet(DATA, 1, b"r")).await?;
+                conn.expect_close(1, CLOSE_NETWORK_ERROR).await
+            })
+            .await,
+        );
+        report.checks.push(
+            check("Target closes the connection", async {
+                let mut conn = Conn::open().await?;
+                conn.send(connect(1, STREAM_TCP, util::FAULT_PORT)).await?;
+                conn.send(packet(DATA, 1, b"c")).await?;
+                conn.expect_close(1, CLOSE_VOLUNTARY).await
+            })
+            .await,
+        );
+        report.checks.push(
+            check("CONNECT with an invalid stream type", async {
+                let mut conn = Conn::open().await?;
+                conn.send(connect(1, 0x7f, util::ECHO_PORT)).await?;
+                conn.expect_close(1, CLOSE_INVALID).await
+            })
+            .await,
+        );
+        report.checks.push(
+            check("Truncated packet", async {
+                let mut conn = Conn::open().await?;
+                conn.send(vec![DATA, 0x01, 0x00]).await?;
+                conn.reaction().await
+            })
+            .await,
+        );
+        report.checks.push(
+            check("Truncated CONNECT", async {
+                let mut conn = Conn::open().await?;
+                conn.send(packet(CONNECT, 1, &[STREAM_TCP, 0x17])).await?;
+                conn.reaction().await
+            })
+            .await,
+        );
+        report.checks.push(
+            check("Unknown packet type", async {
+                let mut conn = Conn::open().await?;
+                conn.send(packet(0xff, 1, b"wispmark")).await?;
+                conn.reaction().await
+            })
+            .await,
+        );
+        report.checks.push(
+            check("DATA for a stream that was never opened", async {
+                let mut conn = Conn::open().await?;
+                conn.send(packet(DATA, 42, b"wispmark")).await?;
+                conn.reaction().await
+            })
+            .await,
+        );
+
+        let recovered = self.measure("recovery", &client_log).await;
+        report.recovered = recovered.as_ref().ok().copied();
+        let baseline = report.baseline;
+        report.checks.push(
+            check("Throughput recovers", async {
+                let bandwidth = recovered?;
+                let Some(baseline) = baseline else {
+                    return Ok((Outcome::Warn, format!("{}, no baseline", bandwidth)));
+                };
+                let before = baseline.up + baseline.down;
+                let ratio = (bandwidth.up + bandwidth.down) / before.max(f64::EPSILON);
+                let outcome = if ratio >= self.recovery {
+                    Outcome::Pass
+                } else {
+                    Outcome::Fail
+                };
+                Ok((
+                    outcome,
+                    format!("{}, {:.0}% of the baseline", bandwidth, ratio * 100.0),
+                ))
+            })
+            .await,
+        );
+
+        benchmark::stop(handle, "server").await;
+    }
+}
+
+pub async fn run(args: FaultArgs, config: &Config) -> Result<()> {
+    let settings = config
+        .run
+        .settings(server::option_sets(&config.server_options, &[], &[])?)?;
+    let options = RunOptions {
+        payload_size: settings.payload_sizes[0],
+        pattern: TrafficPattern::Echo,
+        transport: Transport::Ws,
+        compression: settings.compressions[0],
+        payload_mode: settings.payload_modes[0],
+    };
+
+    let servers: Vec<Box<dyn WispServer>> = server::get_implementations(&settings.server_options)
+        .into_iter()
+        .filter(|s| util::name_matches(s.name(), &args.servers))
+        .collect();
+    let Some(client) = client::get_matrix(&[args.streams], &[1])
+        .into_iter()
+        .find(|c| util::name_matches(c.name(), std::slice::from_ref(&args.client)))
+    else {
+        bail!(
+            "Unknown client {}, expected wisp-js or wisp-mux",
+            args.client
+        );
+    };
+    if servers.is_empty() {
+        bail!("No server matches the selected implementations");
+    }
+
+    util::sudo()?;
+    let _placement = placement::activate(&settings.placement)?;
+    if !settings.placement.is_empty() {
+        println!("Placement: {}", settings.placement);
+    }
+    echo::get_echo().await?;
+    let clients = vec![client];
+    let mut system = SystemInfo::collect();
+    let failed = manage::install_missing(&servers, &clients, settings.jobs);
+    if !failed.is_empty() {
+        let failed: Vec<String> = failed.into_iter().collect();
+        bail!("Failed to install {}", failed.join(", "));
+    }
+    system.record_versions(&servers, &clients);
+    let client = clients.into_iter().next().unwrap();
+    let log_dir = util::log_dir();
+    tokio::fs::create_dir_all(&log_dir).await?;
+
+    let mut results = FaultResults {
+        system,
+        client: client.name().to_string(),
+        duration: args.duration.unwrap_or(settings.duration),
+        partial: true,
+        reports: Vec::new(),
+    };
+    let target = echo::run_target(TrafficPattern::Echo, false)?;
+    util::wait_for_tcp(util::ECHO_PORT, util::SERVER_TIMEOUT).await?;
+    let listener = TcpListener::bind(("127.0.0.1", util::FAULT_PORT))
+        .await
+        .with_context(|| format!("Failed to listen on port {}", util::FAULT_PORT))?;
+    let fault_target = tokio::spawn(fault_target(listener));
+
+    let faults = Faults {
+        client: client.as_ref(),
+        options,
+        duration: results.duration,
+        recovery: args.recovery,
+        log_dir: &log_dir,
+    };
+    let measure = async {
+        for server in &servers {
+            println!("\nInjecting faults into {}", server.name());
+            results.reports.push(FaultReport {
+                server: server.name().to_string(),
+                checks: Vec::new(),
+                baseline: None,
+                recovered: None,
+            });
+            faults
+                .run(server.as_ref(), results.reports.last_mut().unwrap())
+                .await;
+        }
+    };
+    let completed = tokio::select! {
+        _ = measure => true,
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!("\nInterrupted, writing the checks run so far...");
+            if let Err(e) = util::kill(util::WISP_PORT) {
+                eprintln!("Warning: Failed to stop the server: {}", e);
+            }
+            false
+        }
+    };
+    fault_target.abort();
+    drop(target);
+    results.partial = !completed;
+
+    let markdown = markdown(&results);
+    println!("{}", markdown);
+    tokio::fs::write(&args.output, &markdown).await?;
+    println!("\nMarkdown results written to: {}", args.output.display());
+    let json_output = args.output.with_extension("json");
+    let json = serde_json::to_string_pretty(&results).context("Failed to serialize results")?;
+    tokio::fs::write(&json_output, json)
+        .await
+        .with_context(|| format!("Failed to write {}", json_output.display()))?;
+    println!("JSON results written to: {}", json_output.display());
+    Ok(())
+}
+
+pub fn markdown(results: &FaultResults) -> String {
+    let mut output = String::new();
+    if results.partial {
+        output.push_str(
+            "**Partial results: the run was interrupted before every check finished.**\n\n",
+        );
+    }
+    output.push_str(&results.system.markdown());
+    output.push_str(&format!(
+        "Client: {}, throughput measured for {}s before and after the faults\n",
+        results.client, results.duration
+    ));
+
+    let mut summary = vec![vec![
+        "Server".to_string(),
+        "Passed".to_string(),
+        "Warnings".to_string(),
+        "Failed".to_string(),
+        "Before".to_string(),
+        "After".to_string(),
+    ]];
+    let bandwidth = |b: Option<Bandwidth>| b.map_or_else(|| "-".to_string(), |b| b.to_string());
+    for report in &results.reports {
+        summary.push(vec![
+            report.server.clone(),
+            report.count(Outcome::Pass).to_string(),
+            report.count(Outcome::Warn).to_string(),
+            report.count(Outcome::Fail).to_string(),
+            bandwidth(report.baseline),
+            bandwidth(report.recovered),
+        ]);
+    }
+    output.push_str("\n## Summary\n\n");
+    output.push_str(&benchmark::format_table(&summary));
+
+    for report in &results.reports {
+        output.push_str(&format!("\n## {}\n\n", report.server));
+        let mut table = vec![vec![
+            "Fault".to_string(),
+            "Result".to_string(),
+            "Details".to_string(),
+        ]];
+        for check in &report.checks {
+            table.push(vec![
+                check.name.clone(),
+                check.outcome.to_string(),
+                check.detail.clone(),
+            ]);
+        }
+        output.push_str(&benchmark::format_table(&table));
+    }
+    output
+}
@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+
+const CHUNK_SIZE: usize = 16 * 1024;
+const QUEUE_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Impairment {
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub rate: Option<usize>,
+    pub reorder: f64,
+    pub stall_every: Option<Duration>,
+    pub stall_for: Duration,
+}
+
+impl fmt::Display for Impairment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ms ± {} ms one-way latency",
+            self.latency.as_millis(),
+            self.jitter.as_millis()
+        )?;
+        if let Some(rate) = self.rate {
+            write!(f, ", {}/s cap", crate::util::format_size(rate))?;
+        }
+        if self.reorder > 0.0 {
+            write!(f, ", {:.1}% reordered", self.reorder * 100.0)?;
+        }
+        if let Some(every) = self.stall_every {
+            write!(
+                f,
+                ", {} ms stall every {} ms",
+                self.stall_for.as_millis(),
+                every.as_millis()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Link {
+    impairment: Impairment,
+    started: Instant,
+    next_free: Mutex<Instant>,
+}
+
+impl Link {
+    fn new(impairment: Impairment) -> Self {
+        let now = Instant::now();
+        Self {
+            impairment,
+            started: now,
+            next_free: Mutex::new(now),
+        }
+    }
+
+    fn delay(&self) -> Duration {
+        let imp = &self.impairment;
+        let jitter = imp.jitter.as_secs_f64() * (rand::random::<f64>() * 2.0 - 1.0);
+        let mut delay = Duration::from_secs_f64((imp.latency.as_secs_f64() + jitter).max(0.0));
+        // a reordered segment holds back everything behind it, like a retransmit would
+        if imp.reorder > 0.0 && rand::random::<f64>() < imp.reorder {
+            delay += imp.latency.max(Duration::from_millis(1)) * 2;
+        }
+        delay
+    }
+
+    async fn wait_for_stall(&self) {
+        let Some(every) = self.impairment.stall_every else {
+            return;
+        };
+        let cycle = every + self.impairment.stall_for;
+        let into = Duration::from_nanos(
+            (self.started.elapsed().as_nanos() % cycle.as_nanos()) as u64,
+        );
+        if into >= every {
+            sleep(cycle - into).await;
+        }
+    }
+
+    async fn wait_for_capacity(&self, bytes: usize) {
+        let Some(rate) = self.impairment.rate else {
+            return;
+        };
+        let cost = Duration::from_secs_f64(bytes as f64 / rate as f64);
+        let deadline = {
+            let mut next_free = self.next_free.lock().await;
+            let start = (*next_free).max(Instant::now());
+            *next_free = start + cost;
+            start
+        };
+        sleep_until(deadline).await;
+    }
+}
+
+pub async fn spawn(
+    listen_port: u16,
+    server_port: u16,
+    impairment: Impairment,
+) -> Result<JoinHandle<Result<()>>> {
+    let listener = TcpListener::bind(("127.0.0.1", listen_port))
+        .await
+        .context("Failed to bind impairment relay")?;
+    let upstream = Arc::new(Link::new(impairment.clone()));
+    let downstream = Arc::new(Link::new(impairment));
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let (client, _) = listener.accept().await?;
+            let upstream = upstream.clone();
+            let downstream = downstream.clone();
+            tokio::spawn(async move {
+                let Ok(server) = TcpStream::connect(("127.0.0.1", server_port)).await else {
+                    return;
+                };
+                let _ = client.set_nodelay(true);
+                let _ = server.set_nodelay(true);
+                let (client_read, client_write) = client.into_split();
+                let (server_read, server_write) = server.into_split();
+                tokio::join!(
+                    relay(client_read, server_write, upstream),
+                    relay(server_read, client_write, downstream),
+                );
+            });
+        }
+    }))
+}
+
+async fn relay(mut reader: OwnedReadHalf, mut writer: OwnedWriteHalf, link: Arc<Link>) {
+    let (tx, mut rx) = mpsc::channel::<(Instant, Vec<u8>)>(QUEUE_DEPTH);
+
+    let read_link = link.clone();
+    let read_task = tokio::spawn(async move {
+        let mut last_due = Instant::now();
+        loop {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            let n = match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            buf.truncate(n);
+            // TCP delivers in order, so a delayed chunk also delays the ones after it
+            let due = (Instant::now() + read_link.delay()).max(last_due);
+            last_due = due;
+            if tx.send((due, buf)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some((due, chunk)) = rx.recv().await {
+        sleep_until(due).await;
+        link.wait_for_stall().await;
+        link.wait_for_capacity(chunk.len()).await;
+        if writer.write_all(&chunk).await.is_err() {
+            break;
+        }
+    }
+
+    read_task.abort();
+    let _ = writer.shutdown().await;
+}
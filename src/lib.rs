@@ -0,0 +1,68 @@
+//! WispMark benchmarks Wisp protocol servers and clients against each other.
+//!
+//! The `wispmark` binary is a thin wrapper around [`BenchmarkRunner`], which can also be used to
+//! benchmark your own [`WispServer`] or [`WispClient`] implementations, e.g. from `cargo test`:
+//!
+//! ```no_run
+//! use wispmark::{BenchmarkResult, BenchmarkRunner, TrafficPattern};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let results = BenchmarkRunner::new()
+//!     .duration(5)
+//!     .servers(["wisp-js"])
+//!     .clients(["wisp-js (10)"])
+//!     .patterns([TrafficPattern::Echo])
+//!     .run()
+//!     .await?;
+//! for entry in &results.results {
+//!     assert!(matches!(entry.result, BenchmarkResult::Success(_)));
+//! }
+//! println!("{}", results.markdown()?);
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod client;
+pub mod fairness;
+pub mod impair;
+pub mod placement;
+pub mod process;
+pub mod report;
+pub mod runner;
+pub mod server;
+pub mod structure;
+pub mod system;
+
+pub(crate) mod benchmark;
+pub(crate) mod config;
+pub(crate) mod doctor;
+pub(crate) mod echo;
+pub(crate) mod faults;
+pub(crate) mod loadgen;
+pub(crate) mod manage;
+pub(crate) mod noise;
+pub(crate) mod offline;
+pub(crate) mod scale;
+pub(crate) mod soak;
+pub(crate) mod util;
+
+mod cli;
+mod embedded;
+mod tls;
+
+pub use benchmark::BaselineResults;
+pub use cli::main as cli;
+pub use config::RunConfig;
+pub use fairness::Fairness;
+pub use impair::Impairment;
+pub use placement::{CpuSet, Placement};
+pub use process::ProcessHandle;
+pub use report::{Checkpoint, ResultEntry, ResultsFile};
+pub use runner::BenchmarkRunner;
+pub use server::OptionSets;
+pub use structure::{
+    Bandwidth, BenchmarkResult, BenchmarkResults, BenchmarkSettings, Compression, LoadShape,
+    PayloadMode, Repeats, RunHandle, RunOptions, ServerOptions, Stats, TrafficPattern,
+    Transport, WispClient, WispServer,
+};
+pub use system::SystemInfo;
@@ -0,0 +1,181 @@
+use crate::echo::{self, Target};
+use crate::placement::{self, Role};
+use crate::structure::{Bandwidth, LoadShape, TrafficPattern};
+use crate::util;
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, Duration, Instant};
+
+#[derive(Args, Debug)]
+pub struct LoadgenArgs {
+    #[arg(long, default_value_t = 10)]
+    pub connections: usize,
+
+    #[arg(long, default_value = "50k", value_parser = util::parse_size)]
+    pub payload_size: usize,
+
+    #[arg(long, default_value_t = 1)]
+    pub depth: usize,
+
+    #[arg(long, value_enum, default_value = "echo")]
+    pub pattern: TrafficPattern,
+
+    #[arg(long, default_value = "127.0.0.1")]
+    pub host: String,
+
+    #[arg(long, default_value_t = util::ECHO_PORT)]
+    pub port: u16,
+
+    #[arg(long, value_parser = util::parse_duration)]
+    pub duration: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Counter {
+    up: AtomicU64,
+    down: AtomicU64,
+}
+
+// without a duration it runs until it's killed, the way the baseline uses it
+pub async fn run(args: LoadgenArgs) -> Result<()> {
+    if args.connections == 0 || args.depth == 0 {
+        bail!("--connections and --depth must be at least 1");
+    }
+    let shape = LoadShape {
+        connections: args.connections,
+        payload_size: args.payload_size,
+        depth: args.depth,
+        pattern: args.pattern,
+    };
+    let address = format!("{}:{}", args.host, args.port);
+    let counter = Arc::new(Counter::default());
+    let generate = generate(shape, address.clone(), counter.clone());
+    let Some(duration) = args.duration else {
+        generate.await;
+        return Ok(());
+    };
+
+    println!(
+        "Sending {} to {} for {}...",
+        shape,
+        address,
+        util::format_duration(duration.as_secs())
+    );
+    let started = Instant::now();
+    let _ = tokio::time::timeout(duration, generate).await;
+    let seconds = started.elapsed().as_secs_f64();
+    let mib = |bytes: &AtomicU64| bytes.load(Ordering::Relaxed) as f64 / 1024.0 / 1024.0 / seconds;
+    println!(
+        "Result: {}",
+        Bandwidth {
+            up: mib(&counter.up),
+            down: mib(&counter.down),
+        }
+    );
+    Ok(())
+}
+
+/// Starts the generator for a shape against the echo port, as a separate wispmark process when
+/// running from the CLI so it doesn't share a runtime with the measurement.
+pub fn spawn(shape: LoadShape) -> Result<Target> {
+    let Some(exe) = echo::target_exe() else {
+        let address = format!("127.0.0.1:{}", util::ECHO_PORT);
+        return Ok(Target::Task(tokio::spawn(async move {
+            generate(shape, address, Arc::default()).await;
+            Ok(())
+        })));
+    };
+    let mut command = Command::new(exe);
+    command
+        .arg("loadgen")
+        .args(["--connections", &shape.connections.to_string()])
+        .args(["--payload-size", &shape.payload_size.to_string()])
+        .args(["--depth", &shape.depth.to_string()])
+        .args(["--pattern", &shape.pattern.to_string()])
+        .args(["--port", &util::ECHO_PORT.to_string()]);
+    placement::apply(Role::Client, &mut command);
+    command
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .map(Target::Process)
+        .context("Failed to run the load generator")
+}
+
+// every connection reconnects when it's closed, until the generator is dropped
+async fn generate(shape: LoadShape, address: String, counter: Arc<Counter>) {
+    let mut connections = JoinSet::new();
+    for _ in 0..shape.connections {
+        let address = address.clone();
+        let counter = counter.clone();
+        connections.spawn(async move {
+            loop {
+                if let Ok(stream) = TcpStream::connect(&address).await {
+                    load(stream, shape, &counter).await;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+}
+
+// echoes are read back concurrently, with at most `depth` payloads written ahead of them
+async fn load(stream: TcpStream, shape: LoadShape, counter: &Counter) {
+    let buffer = vec![0u8; shape.payload_size];
+    let mut read_buf = vec![0u8; shape.payload_size];
+    let (mut reader, mut writer) = stream.into_split();
+    let sent = |n: usize| counter.up.fetch_add(n as u64, Ordering::Relaxed);
+    let received = |n: usize| counter.down.fetch_add(n as u64, Ordering::Relaxed);
+
+    match shape.pattern {
+        TrafficPattern::Echo => {
+            let window = Semaphore::new(shape.depth.max(1));
+            let write = async {
+                while let Ok(permit) = window.acquire().await {
+                    permit.forget();
+                    if writer.write_all(&buffer).await.is_err() {
+                        break;
+                    }
+                    sent(buffer.len());
+                }
+            };
+            let read = async {
+                let mut pending = 0;
+                while let Ok(n) = reader.read(&mut read_buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                    received(n);
+                    pending += n;
+                    window.add_permits(pending / shape.payload_size);
+                    pending %= shape.payload_size;
+                }
+            };
+            tokio::select! {
+                _ = write => {}
+                _ = read => {}
+            }
+        }
+        TrafficPattern::Upload => {
+            while writer.write_all(&buffer).await.is_ok() {
+                sent(buffer.len());
+            }
+        }
+        TrafficPattern::Download => {
+            while let Ok(n) = reader.read(&mut read_buf).await {
+                if n == 0 {
+                    break;
+                }
+                received(n);
+            }
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use crate::server::OptionSets;
+use crate::structure::{WispClient, WispServer};
+use crate::{benchmark, client, server, util};
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+pub fn install_checked(
+    run: impl FnOnce() -> Result<()>,
+    check: impl FnOnce() -> bool,
+) -> Result<()> {
+    run()?;
+    if !check() {
+        bail!("install finished but the implementation is still missing");
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Action<'a> {
+    Install,
+    Update(Option<&'a str>),
+}
+
+impl Action<'_> {
+    fn log_kind(&self) -> &'static str {
+        match self {
+            Action::Install => "INSTALL",
+            Action::Update(_) => "UPDATE",
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        match self {
+            Action::Install => "install",
+            Action::Update(_) => "update",
+        }
+    }
+}
+
+struct Job<'a> {
+    name: &'a str,
+    path: &'a Path,
+    run: Box<dyn FnOnce() -> Result<()> + Send + 'a>,
+}
+
+fn server_job<'a>(server: &'a dyn WispServer, action: Action<'a>) -> Job<'a> {
+    Job {
+        name: server.name(),
+        path: server.path(),
+        run: Box::new(move || {
+            let log_file = log_file(action.log_kind(), server.name())?;
+            install_checked(
+                || match action {
+                    Action::Install => server.install(&log_file),
+                    Action::Update(reference) => server.update(reference, &log_file),
+                },
+                || server.check_install(),
+            )
+        }),
+    }
+}
+
+fn client_job<'a>(client: &'a dyn WispClient, action: Action<'a>) -> Job<'a> {
+    Job {
+        name: client.name(),
+        path: client.path(),
+        run: Box::new(move || {
+            let log_file = log_file(action.log_kind(), client.name())?;
+            install_checked(
+                || match action {
+                    Action::Install => client.install(&log_file),
+                    Action::Update(reference) => client.update(reference, &log_file),
+                },
+                || client.check_install(),
+            )
+        }),
+    }
+}
+
+// build output goes to the log files, so only progress lines reach the terminal. Jobs sharing a
+// checkout, like epoxy-server and wisp-mux, run one after the other.
+fn run_jobs(jobs: Vec<Job>, limit: usize, verb: &str) -> Vec<String> {
+    let total = jobs.len();
+    let checkouts: HashMap<&Path, Mutex<()>> =
+        jobs.iter().map(|job| (job.path, Mutex::new(()))).collect();
+    let queue = Mutex::new(jobs.into_iter().enumerate());
+    let failed = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..limit.clamp(1, total.max(1)) {
+            scope.spawn(|| loop {
+                let Some((i, job)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let _checkout = checkouts[job.path].lock().unwrap();
+                println!("[{}/{}] Starting {} of {}", i + 1, total, verb, job.name);
+                let started = Instant::now();
+                match (job.run)() {
+                    Ok(()) => println!(
+                        "[{}/{}] Finished {} of {} in {:.1}s",
+                        i + 1,
+                        total,
+                        verb,
+                        job.name,
+                        started.elapsed().as_secs_f64()
+                    ),
+                    Err(e) => {
+                        eprintln!(
+                            "[{}/{}] Failed to {} {}: {:#}",
+                            i + 1,
+                            total,
+                            verb,
+                            job.name,
+                            e
+                        );
+                        failed.lock().unwrap().push(job.name.to_string());
+                    }
+                }
+            });
+        }
+    });
+
+    failed.into_inner().unwrap()
+}
+
+pub fn install_missing(
+    servers: &[Box<dyn WispServer>],
+    clients: &[Box<dyn WispClient>],
+    jobs: usize,
+) -> HashSet<String> {
+    let mut server_paths = HashSet::new();
+    let mut client_paths = HashSet::new();
+    let mut queue = Vec::new();
+    for server in servers {
+        if !server.check_install() && server_paths.insert(server.path()) {
+            queue.push(server_job(server.as_ref(), Action::Install));
+        }
+    }
+    for client in clients {
+        if !client.check_install() && client_paths.insert(client.path()) {
+            queue.push(client_job(client.as_ref(), Action::Install));
+        }
+    }
+    if queue.is_empty() {
+        return HashSet::new();
+    }
+    run_jobs(queue, jobs, "install");
+
+    // variants sharing a failed checkout are reported as failed too
+    servers
+        .iter()
+        .filter(|s| !s.check_install())
+        .map(|s| s.name().to_string())
+        .chain(
+            clients
+                .iter()
+                .filter(|c| !c.check_install())
+                .map(|c| c.name().to_string()),
+        )
+        .collect()
+}
+
+struct Selection {
+    servers: Vec<Box<dyn WispServer>>,
+    clients: Vec<Box<dyn WispClient>>,
+}
+
+// variants sharing a checkout are only handled once per side
+fn select(names: &[String]) -> Result<Selection> {
+    let mut server_paths: HashSet<PathBuf> = HashSet::new();
+    let mut client_paths: HashSet<PathBuf> = HashSet::new();
+    let mut matched: HashSet<&String> = HashSet::new();
+    let mut note = |name: &str| {
+        for n in names {
+            if util::name_matches(name, std::slice::from_ref(n)) {
+                matched.insert(n);
+            }
+        }
+        util::name_matches(name, names)
+    };
+
+    let servers: Vec<_> = server::get_implementations(&OptionSets::new())
+        .into_iter()
+        .filter(|s| note(s.name()) && server_paths.insert(s.path().to_path_buf()))
+        .collect();
+    let clients: Vec<_> = client::get_implementations()
+        .into_iter()
+        .filter(|c| note(c.name()) && client_paths.insert(c.path().to_path_buf()))
+        .collect();
+
+    let unknown: Vec<&str> = names
+        .iter()
+        .filter(|n| !matched.contains(n))
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        bail!(
+            "Unknown implementation(s): {}, see `wispmark list`",
+            unknown.join(", ")
+        );
+    }
+    Ok(Selection { servers, clients })
+}
+
+fn log_file(kind: &str, name: &str) -> Result<PathBuf> {
+    std::fs::create_dir_all(util::log_dir())?;
+    Ok(util::log_dir().join(format!("{}_{}.log", kind, util::log_name(name))))
+}
+
+fn report(failed: Vec<String>, what: &str) -> Result<()> {
+    if !failed.is_empty() {
+        bail!("Failed to {} {}", what, failed.join(", "));
+    }
+    Ok(())
+}
+
+pub fn install(names: &[String], jobs: usize) -> Result<()> {
+    run_selected(names, jobs, Action::Install)
+}
+
+pub fn update(names: &[String], reference: Option<&str>, jobs: usize) -> Result<()> {
+    run_selected(names, jobs, Action::Update(reference))?;
+    let selection = select(names)?;
+    for server in &selection.servers {
+        println!("{} is at {}", server.name(), version(server.version()));
+    }
+    for client in &selection.clients {
+        println!("{} is at {}", client.name(), version(client.version()));
+    }
+    Ok(())
+}
+
+fn run_selected(names: &[String], jobs: usize, action: Action) -> Result<()> {
+    let selection = select(names)?;
+    let queue = selection
+        .servers
+        .iter()
+        .map(|s| server_job(s.as_ref(), action))
+        .chain(
+            selection
+                .clients
+                .iter()
+                .map(|c| client_job(c.as_ref(), action)),
+        )
+        .collect();
+    report(run_jobs(queue, jobs, action.verb()), action.verb())
+}
+
+fn version(version: Option<String>) -> String {
+    version.unwrap_or_else(|| "-".to_string())
+}
+
+pub fn clean(names: &[String]) -> Result<()> {
+    let selection = select(names)?;
+
+    for server in &selection.servers {
+        println!("Cleaning {} ({})", server.name(), server.path().display());
+        server.clean()?;
+    }
+
+    for client in &selection.clients {
+        println!("Cleaning {} ({})", client.name(), client.path().display());
+        client.clean()?;
+    }
+
+    Ok(())
+}
+
+pub fn list() -> Result<()> {
+    let status = |installed: bool| if installed { "installed" } else { "missing" }.to_string();
+    let mut table = vec![vec![
+        "Implementation".to_string(),
+        "Kind".to_string(),
+        "Status".to_string(),
+        "Version".to_string(),
+        "Path".to_string(),
+    ]];
+
+    for server in server::get_implementations(&OptionSets::new()) {
+        table.push(vec![
+            server.name().to_string(),
+            "server".to_string(),
+            status(server.check_install()),
+            version(server.version()),
+            server.path().display().to_string(),
+        ]);
+    }
+
+    for client in client::get_implementations() {
+        table.push(vec![
+            client.name().to_string(),
+            "client".to_string(),
+            status(client.check_install()),
+            version(client.version()),
+            client.path().display().to_string(),
+        ]);
+    }
+
+    println!("{}", benchmark::format_table(&table));
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use crate::system;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use tokio::time::{sleep, Duration};
+
+pub const DEFAULT_MAX_NOISE: f64 = 0.1;
+
+/// CPU time of the whole machine and of WispMark with everything it started, in clock ticks.
+pub struct Snapshot {
+    busy: u64,
+    total: u64,
+    own: u64,
+}
+
+impl Snapshot {
+    pub fn take() -> Option<Self> {
+        let stat = std::fs::read_to_string("/proc/stat").ok()?;
+        let ticks: Vec<u64> = stat
+            .lines()
+            .next()?
+            .strip_prefix("cpu ")?
+            .split_whitespace()
+            .filter_map(|t| t.parse().ok())
+            .collect();
+        // user, nice, system, idle, iowait, irq, softirq and steal, guest time is already
+        // counted in user and nice
+        let total: u64 = ticks.iter().take(8).sum();
+        let idle = ticks.get(3)? + ticks.get(4).copied().unwrap_or_default();
+        Some(Self {
+            busy: total - idle,
+            total,
+            own: own_ticks(),
+        })
+    }
+
+    /// Share of the machine's CPU time that other processes used since the snapshot.
+    pub fn external(&self) -> Option<f64> {
+        let now = Self::take()?;
+        let total = now.total.checked_sub(self.total).filter(|&t| t > 0)?;
+        let busy = now.busy.saturating_sub(self.busy);
+        let own = now.own.saturating_sub(self.own);
+        Some(busy.saturating_sub(own) as f64 / total as f64)
+    }
+}
+
+// processes that exited in between were reaped by their parent, which then counts their time
+// as its children's, so the sum only grows
+fn own_ticks() -> u64 {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return 0;
+    };
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    let mut ticks: HashMap<u32, u64> = HashMap::new();
+    for entry in entries.flatten() {
+        let Ok(pid) = entry.file_name().to_string_lossy().parse::<u32>() else {
+            continue;
+        };
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        // the command name may contain spaces, so fields are counted from the closing paren
+        let Some((_, fields)) = stat.rsplit_once(')') else {
+            continue;
+        };
+        let fields: Vec<u64> = fields
+            .split_whitespace()
+            .skip(1)
+            .map(|f| f.parse().unwrap_or_default())
+            .collect();
+        if fields.len() < 15 {
+            continue;
+        }
+        children.entry(fields[0] as u32).or_default().push(pid);
+        // utime, stime, cutime and cstime
+        ticks.insert(pid, fields[10..14].iter().sum());
+    }
+
+    let mut pids = vec![std::process::id()];
+    let mut i = 0;
+    while i < pids.len() {
+        if let Some(more) = children.get(&pids[i]) {
+            pids.extend(more);
+        }
+        i += 1;
+    }
+    pids.iter().filter_map(|pid| ticks.get(pid)).sum()
+}
+
+/// Refuses to start, or warns, when other processes keep the machine busy. The load average
+/// trails behind by minutes, so it only ever warns.
+pub async fn check(max_noise: f64, abort: bool) -> Result<()> {
+    let Some(before) = Snapshot::take() else {
+        return Ok(());
+    };
+    sleep(Duration::from_secs(1)).await;
+    let external = before.external().unwrap_or_default();
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let load = system::load().map_or(0.0, |[one, _, _]| one);
+
+    if external > max_noise {
+        let busy = format!(
+            "The machine is busy: other processes used {:.0}% of the CPU time",
+            external * 100.0
+        );
+        if abort {
+            bail!("{}, stop them or raise --max-noise", busy);
+        }
+        eprintln!("Warning: {}, results may be noisy", busy);
+    } else if load >= cpus as f64 {
+        eprintln!(
+            "Warning: The load average is {:.2} on {} CPUs, results may be noisy",
+            load, cpus
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use crate::server::OptionSets;
+use crate::{client, echo, server, util};
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub fn fetch(output: &Path) -> Result<()> {
+    let mirror = util::base().join("offline");
+    std::fs::create_dir_all(&mirror).context("Failed to create offline directory")?;
+    std::fs::create_dir_all(util::log_dir()).context("Failed to create log directory")?;
+    let fetch_log = |name: &str| {
+        util::log_dir().join(format!("FETCH_{}.log", util::log_name(name)))
+    };
+
+    for server in server::get_implementations(&OptionSets::new()) {
+        println!("Fetching {}", server.name());
+        server.fetch(&mirror, &fetch_log(server.name()))?;
+    }
+
+    for client in client::get_implementations() {
+        println!("Fetching {}", client.name());
+        client.fetch(&mirror, &fetch_log(client.name()))?;
+    }
+
+    println!("Fetching echo");
+    echo::fetch(&mirror, &fetch_log("echo"))?;
+
+    println!("Packing {}", output.display());
+    util::run_logged(
+        Command::new("tar")
+            .arg("czf")
+            .arg(output)
+            .arg("-C")
+            .arg(&mirror)
+            .arg("."),
+        &fetch_log("archive"),
+        "create offline archive",
+    )?;
+
+    println!("Offline archive written to: {}", output.display());
+    Ok(())
+}
+
+pub fn prepare(source: &Path) -> Result<PathBuf> {
+    if source.is_dir() {
+        return source
+            .canonicalize()
+            .context("Failed to resolve offline mirror");
+    }
+    if !source.exists() {
+        return Err(anyhow!("Offline source {} does not exist", source.display()));
+    }
+
+    let mirror = util::base().join("offline");
+    std::fs::create_dir_all(&mirror).context("Failed to create offline directory")?;
+    std::fs::create_dir_all(util::log_dir()).context("Failed to create log directory")?;
+    println!("Unpacking {} to {}", source.display(), mirror.display());
+    util::run_logged(
+        Command::new("tar")
+            .arg("xzf")
+            .arg(source)
+            .arg("-C")
+            .arg(&mirror),
+        &util::log_dir().join("OFFLINE_unpack.log"),
+        "extract offline archive",
+    )?;
+
+    Ok(mirror)
+}
+
+pub fn fetch_repo(url: &str, mirror: &Path, log_file: &Path) -> Result<PathBuf> {
+    let name = url.trim_end_matches('/').rsplit('/').next().unwrap_or(url);
+    let repo = mirror.join("git").join(name);
+
+    if !repo.exists() {
+        std::fs::create_dir_all(mirror.join("git"))?;
+        util::clone(url, &repo, log_file)?;
+    }
+    Ok(repo)
+}
+
+pub fn vendor_cargo(repo: &Path, log_file: &Path) -> Result<()> {
+    if repo.join("vendor").exists() {
+        return Ok(());
+    }
+
+    let output = Command::new("cargo")
+        .args(["vendor", "vendor"])
+        .current_dir(repo)
+        .output()
+        .context("Failed to run cargo vendor")?;
+    std::fs::write(log_file, &output.stderr).context("Failed to write log file")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Failed to vendor {} ({}), see {}",
+            repo.display(),
+            output.status,
+            log_file.display()
+        ));
+    }
+
+    let config_dir = repo.join(".cargo");
+    std::fs::create_dir_all(&config_dir)?;
+    let config_path = config_dir.join("config.toml");
+    let mut config = std::fs::read_to_string(&config_path).unwrap_or_default();
+    config.push('\n');
+    config.push_str(&String::from_utf8_lossy(&output.stdout));
+    std::fs::write(&config_path, config).context("Failed to write cargo config")?;
+
+    Ok(())
+}
+
+pub fn vendor_go(repo: &Path, log_file: &Path) -> Result<()> {
+    util::run_logged(
+        Command::new("go").args(["mod", "vendor"]).current_dir(repo),
+        log_file,
+        "vendor go-wisp",
+    )
+}
+
+pub fn download_wheels(python: &str, repo: &Path, mirror: &Path, log_file: &Path) -> Result<()> {
+    util::run_logged(
+        Command::new(python)
+            .args(["-m", "pip", "download", "-d"])
+            .arg(mirror.join("wheels"))
+            .arg(repo)
+            .args(["setuptools", "wheel"]),
+        log_file,
+        "download Python packages",
+    )
+}
+
+pub fn cache_npm(dir: &Path, mirror: &Path, log_file: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    util::run_logged(
+        Command::new("npm")
+            .arg("i")
+            .arg("--cache")
+            .arg(mirror.join("npm-cache"))
+            .current_dir(dir),
+        log_file,
+        "cache npm packages",
+    )
+}
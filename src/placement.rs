@@ -0,0 +1,337 @@
+use crate::util;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::{Mutex, Once};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CGROUP: &str = "wispmark-server";
+const CPU_PERIOD: u64 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    Client,
+    Echo,
+}
+
+/// A list of CPU numbers, written like `0-3,8`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CpuSet(Vec<usize>);
+
+impl CpuSet {
+    pub fn cpus(&self) -> &[usize] {
+        &self.0
+    }
+
+    fn overlaps(&self, other: &CpuSet) -> bool {
+        self.0.iter().any(|cpu| other.0.contains(cpu))
+    }
+
+    fn to_libc(&self) -> libc::cpu_set_t {
+        // SAFETY: cpu_set_t is a plain bitmask and CPU_SET only writes inside it, every CPU
+        // having been checked against CPU_SETSIZE
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for &cpu in &self.0 {
+                libc::CPU_SET(cpu, &mut set);
+            }
+            set
+        }
+    }
+}
+
+impl FromStr for CpuSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid CPU list: {}, expected e.g. 0-3,8", s);
+        let mut cpus = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let first: usize = first.trim().parse().map_err(|_| invalid())?;
+            let last: usize = last.trim().parse().map_err(|_| invalid())?;
+            if first > last || last >= libc::CPU_SETSIZE as usize {
+                return Err(invalid());
+            }
+            cpus.extend(first..=last);
+        }
+        if cpus.is_empty() {
+            return Err(invalid());
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(Self(cpus))
+    }
+}
+
+impl TryFrom<String> for CpuSet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CpuSet> for String {
+    fn from(set: CpuSet) -> Self {
+        set.to_string()
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ranges: Vec<String> = Vec::new();
+        let mut i = 0;
+        while i < self.0.len() {
+            let start = self.0[i];
+            while i + 1 < self.0.len() && self.0[i + 1] == self.0[i] + 1 {
+                i += 1;
+            }
+            ranges.push(match self.0[i] {
+                end if end == start => start.to_string(),
+                end => format!("{}-{}", start, end),
+            });
+            i += 1;
+        }
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+/// Which CPUs the server, clients and echo target run on, and how much the server may use.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Placement {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_cpus: Option<CpuSet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cpus: Option<CpuSet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo_cpus: Option<CpuSet>,
+    /// CPU time the server may use, in CPUs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_cpu_limit: Option<f64>,
+    /// Memory the server may use, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_memory_limit: Option<usize>,
+}
+
+impl Placement {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn limited(&self) -> bool {
+        self.server_cpu_limit.is_some() || self.server_memory_limit.is_some()
+    }
+
+    fn cpus(&self, role: Role) -> Option<&CpuSet> {
+        match role {
+            Role::Server => self.server_cpus.as_ref(),
+            Role::Client => self.client_cpus.as_ref(),
+            Role::Echo => self.echo_cpus.as_ref(),
+        }
+    }
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cpus = |set: &Option<CpuSet>| {
+            set.as_ref()
+                .map_or("any CPU".to_string(), |set| match set.cpus() {
+                    [_] => format!("CPU {}", set),
+                    _ => format!("CPUs {}", set),
+                })
+        };
+        let mut limits = Vec::new();
+        if let Some(cpu) = self.server_cpu_limit {
+            limits.push(format!("{} CPUs", cpu));
+        }
+        if let Some(memory) = self.server_memory_limit {
+            limits.push(util::format_size(memory));
+        }
+        write!(f, "server on {}", cpus(&self.server_cpus))?;
+        if !limits.is_empty() {
+            write!(f, " (limited to {})", limits.join(" and "))?;
+        }
+        write!(
+            f,
+            ", clients on {}, echo on {}",
+            cpus(&self.client_cpus),
+            cpus(&self.echo_cpus)
+        )
+    }
+}
+
+struct Active {
+    placement: Placement,
+    cgroup: Option<File>,
+}
+
+static ACTIVE: Mutex<Option<Active>> = Mutex::new(None);
+static CGROUP_WARNING: Once = Once::new();
+
+/// Keeps a placement active until it's dropped, then removes the server cgroup.
+pub struct Activation(());
+
+impl Drop for Activation {
+    fn drop(&mut self) {
+        let Some(active) = ACTIVE.lock().unwrap().take() else {
+            return;
+        };
+        if let Some(cgroup) = active.cgroup {
+            drop(cgroup);
+            let dir = Path::new(CGROUP_ROOT).join(CGROUP);
+            if let Err(e) = std::fs::remove_dir(&dir) {
+                eprintln!("Warning: Failed to remove cgroup {}: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+fn allowed_cpus() -> Result<Vec<usize>> {
+    // SAFETY: sched_getaffinity only writes inside the set it's given
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to read CPU affinity");
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+            .collect())
+    }
+}
+
+// the cgroup sits directly under the root, the only cgroup that may have both processes and
+// children with controllers enabled
+fn server_cgroup(placement: &Placement) -> Result<File> {
+    let root = Path::new(CGROUP_ROOT);
+    if !root.join("cgroup.controllers").exists() {
+        bail!("Server limits need cgroup v2 mounted at {}", CGROUP_ROOT);
+    }
+    let subtree_control = root.join("cgroup.subtree_control");
+    let enabled = std::fs::read_to_string(&subtree_control).unwrap_or_default();
+    let enabled: Vec<&str> = enabled.split_whitespace().collect();
+    if !enabled.contains(&"cpu") || !enabled.contains(&"memory") {
+        std::fs::write(&subtree_control, "+cpu +memory")
+            .context("Failed to enable the cpu and memory controllers, server limits need root")?;
+    }
+    let dir = root.join(CGROUP);
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create cgroup {}", dir.display()))?;
+
+    let write = |file: &str, value: String| {
+        std::fs::write(dir.join(file), value)
+            .with_context(|| format!("Failed to write {}", dir.join(file).display()))
+    };
+    write(
+        "cpu.max",
+        match placement.server_cpu_limit {
+            Some(cpus) => format!("{} {}", (cpus * CPU_PERIOD as f64) as u64, CPU_PERIOD),
+            None => format!("max {}", CPU_PERIOD),
+        },
+    )?;
+    write(
+        "memory.max",
+        placement
+            .server_memory_limit
+            .map_or("max".to_string(), |bytes| bytes.to_string()),
+    )?;
+    OpenOptions::new()
+        .write(true)
+        .open(dir.join("cgroup.procs"))
+        .with_context(|| format!("Failed to open {}/cgroup.procs", dir.display()))
+}
+
+/// Checks the placement and sets up the server cgroup. Processes spawned through [`apply`]
+/// are placed accordingly until the returned [`Activation`] is dropped.
+pub fn activate(placement: &Placement) -> Result<Activation> {
+    let allowed = allowed_cpus()?;
+    let sets = [
+        ("server", &placement.server_cpus),
+        ("client", &placement.client_cpus),
+        ("echo", &placement.echo_cpus),
+    ];
+    for (role, set) in sets {
+        let Some(set) = set else { continue };
+        if let Some(cpu) = set.cpus().iter().find(|cpu| !allowed.contains(cpu)) {
+            bail!(
+                "CPU {} for the {} isn't available, this machine allows {}",
+                cpu,
+                role,
+                CpuSet(allowed)
+            );
+        }
+    }
+    for (i, (role, set)) in sets.iter().enumerate() {
+        for (other, other_set) in &sets[i + 1..] {
+            if let (Some(a), Some(b)) = (set, other_set) {
+                if a.overlaps(b) {
+                    eprintln!("Warning: The {} and {} CPUs overlap", role, other);
+                }
+            }
+        }
+    }
+
+    if placement.server_cpu_limit.is_some_and(|cpus| cpus < 0.01) {
+        bail!("The server CPU limit must be at least 0.01 CPUs");
+    }
+    let cgroup = if placement.limited() {
+        match server_cgroup(placement) {
+            Ok(cgroup) => Some(cgroup),
+            Err(e) => {
+                CGROUP_WARNING.call_once(|| {
+                    eprintln!("Warning: The server runs without its limits: {:#}", e)
+                });
+                None
+            }
+        }
+    } else {
+        None
+    };
+    *ACTIVE.lock().unwrap() = Some(Active {
+        placement: placement.clone(),
+        cgroup,
+    });
+    Ok(Activation(()))
+}
+
+/// Pins a command to the CPUs of its role and moves servers into the limited cgroup.
+pub fn apply(role: Role, command: &mut Command) {
+    let active = ACTIVE.lock().unwrap();
+    let Some(active) = active.as_ref() else {
+        return;
+    };
+    let cpus = active.placement.cpus(role).map(CpuSet::to_libc);
+    let cgroup: Option<RawFd> = match role {
+        Role::Server => active.cgroup.as_ref().map(File::as_raw_fd),
+        Role::Client | Role::Echo => None,
+    };
+    if cpus.is_none() && cgroup.is_none() {
+        return;
+    }
+
+    // SAFETY: the closure runs between fork and exec, so it only makes async-signal-safe
+    // system calls. The cgroup file stays open for as long as the placement is active.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(fd) = cgroup {
+                if libc::write(fd, b"0".as_ptr().cast(), 1) != 1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(set) = &cpus {
+                if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), set) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,162 @@
+use crate::structure::{RunHandle, Stats};
+use crate::util;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use std::process::Stdio;
+use std::sync::OnceLock;
+use tokio::process::{Child, Command};
+
+// the kernel reports cpu time in clock ticks
+fn clock_ticks() -> f64 {
+    static TICKS: OnceLock<f64> = OnceLock::new();
+    *TICKS.get_or_init(|| match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as f64,
+        _ => 100.0,
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Readiness {
+    Started,
+    Tcp(u16),
+    Http(u16),
+}
+
+/// A [`RunHandle`] for implementations that run as external processes.
+pub struct ProcessHandle {
+    children: Vec<Child>,
+    readiness: Readiness,
+}
+
+impl ProcessHandle {
+    /// A server that is ready once it answers HTTP on `port`, or accepts TCP when it
+    /// terminates TLS itself.
+    pub fn server(child: Child, port: u16, tls: bool) -> Self {
+        Self {
+            children: vec![child],
+            readiness: if tls {
+                Readiness::Tcp(port)
+            } else {
+                Readiness::Http(port)
+            },
+        }
+    }
+
+    /// Client processes that are ready as soon as they are started.
+    pub fn clients(children: Vec<Child>) -> Self {
+        Self {
+            children,
+            readiness: Readiness::Started,
+        }
+    }
+
+    // wrappers like `bash -c` leave the real implementation a level down
+    fn descendants(&self) -> Vec<u32> {
+        let mut pids: Vec<u32> = self.children.iter().filter_map(Child::id).collect();
+        let direct = pids.len();
+        let mut i = 0;
+        while i < pids.len() {
+            pids.extend(children_of(pids[i]));
+            i += 1;
+        }
+        pids.split_off(direct)
+    }
+}
+
+fn children_of(pid: u32) -> Vec<u32> {
+    std::fs::read_to_string(format!("/proc/{}/task/{}/children", pid, pid))
+        .map(|s| {
+            s.split_whitespace()
+                .filter_map(|p| p.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn process_stats(pid: u32) -> Option<Stats> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let rss = status
+        .lines()
+        .find(|l| l.starts_with("VmRSS:"))
+        .and_then(|l| l.split_whitespace().nth(1)?.parse::<u64>().ok())
+        .map(|kib| kib * 1024);
+    let open_fds = std::fs::read_dir(format!("/proc/{}/fd", pid))
+        .ok()
+        .map(|entries| entries.count());
+    // the command name may contain spaces, so fields are counted from the closing paren
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok());
+    let cpu_time = match (ticks(11), ticks(12)) {
+        (Some(user), Some(system)) => Some((user + system) as f64 / clock_ticks()),
+        _ => None,
+    };
+    Some(Stats {
+        rss,
+        open_fds,
+        cpu_time,
+    })
+}
+
+#[async_trait]
+impl RunHandle for ProcessHandle {
+    async fn ready(&mut self) -> Result<()> {
+        let readiness = self.readiness;
+        let wait = async {
+            match readiness {
+                Readiness::Started => Ok(()),
+                Readiness::Tcp(port) => util::wait_for_tcp(port, util::SERVER_TIMEOUT).await,
+                Readiness::Http(port) => util::wait_for_http(port, util::SERVER_TIMEOUT).await,
+            }
+        };
+        let exited = async {
+            loop {
+                for child in &mut self.children {
+                    if let Ok(Some(status)) = child.try_wait() {
+                        return status;
+                    }
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        };
+        tokio::select! {
+            biased;
+            result = wait => result,
+            status = exited => bail!("Process exited early with {}", status),
+        }
+    }
+
+    async fn stats(&mut self) -> Stats {
+        let mut total = Stats::default();
+        let direct = self.children.iter().filter_map(Child::id);
+        for pid in direct.chain(self.descendants()) {
+            if let Some(stats) = process_stats(pid) {
+                total.add(&stats);
+            }
+        }
+        total
+    }
+
+    async fn shutdown(mut self: Box<Self>) -> Result<()> {
+        let descendants: Vec<String> = self
+            .descendants()
+            .into_iter()
+            .map(|pid| pid.to_string())
+            .collect();
+        for child in &mut self.children {
+            let _ = child.start_kill();
+        }
+        if !descendants.is_empty() {
+            let _ = Command::new("kill")
+                .arg("-9")
+                .args(&descendants)
+                .stderr(Stdio::null())
+                .status()
+                .await;
+        }
+        for child in &mut self.children {
+            child.wait().await?;
+        }
+        Ok(())
+    }
+}
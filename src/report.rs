@@ -0,0 +1,411 @@
+use crate::benchmark::{self, BaselineResults};
+use crate::config::RunConfig;
+use crate::fairness::Fairness;
+use crate::structure::{
+    Bandwidth, BenchmarkResult, BenchmarkResults, BenchmarkSettings, LoadShape, Repeats, RunOptions,
+};
+use crate::system::SystemInfo;
+use crate::util;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize)]
+pub struct ResultsFile {
+    pub cpu: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<SystemInfo>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub run: Option<RunConfig>,
+    #[serde(default)]
+    pub partial: bool,
+    pub settings: BenchmarkSettings,
+    pub results: Vec<ResultEntry>,
+    pub baseline: Option<BaselineResults>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tls_proxied: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResultEntry {
+    pub server: String,
+    pub client: String,
+    pub options: RunOptions,
+    pub result: BenchmarkResult,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fairness: Option<Fairness>,
+    /// Share of the CPU time other processes used while the pair was measured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noise: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeats: Option<Repeats>,
+}
+
+impl ResultsFile {
+    pub fn new(
+        results: &BenchmarkResults,
+        cpu: String,
+        settings: BenchmarkSettings,
+        baseline: Option<BaselineResults>,
+    ) -> Self {
+        let mut entries = Vec::new();
+        for server in &results.server_order {
+            for client in &results.client_order {
+                for options in &results.option_order {
+                    if let Some(result) = results.get(server, client, options) {
+                        entries.push(ResultEntry {
+                            server: server.clone(),
+                            client: client.clone(),
+                            options: *options,
+                            result: result.clone(),
+                            fairness: None,
+                            noise: None,
+                            repeats: None,
+                        });
+                    }
+                }
+            }
+        }
+
+        Self {
+            cpu,
+            system: None,
+            profile: None,
+            run: None,
+            partial: false,
+            settings,
+            results: entries,
+            baseline,
+            tls_proxied: results.tls_proxied.clone(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).context("Failed to serialize results")?;
+        std::fs::write(path, json).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn benchmark_results(&self) -> BenchmarkResults {
+        let mut results = BenchmarkResults::new();
+        for entry in &self.results {
+            results.add(
+                entry.server.clone(),
+                entry.client.clone(),
+                entry.options,
+                entry.result.clone(),
+            );
+        }
+        results.tls_proxied = self.tls_proxied.clone();
+        results
+    }
+
+    pub fn markdown(&self) -> Result<String> {
+        let mut output = String::new();
+        if self.partial {
+            output.push_str(
+                "**Partial results: the run was interrupted before every pair finished.**\n\n",
+            );
+        }
+        output += &benchmark::format_results(
+            &self.benchmark_results(),
+            &self.cpu,
+            self.system.as_ref(),
+            &self.settings,
+            &self.baseline,
+        );
+        output.push_str(&self.fairness_table());
+        output.push_str(&self.repeats_table());
+        output.push_str(&self.noise_table());
+        if let (Some(profile), Some(run)) = (&self.profile, &self.run) {
+            let resolved = toml::to_string(run).context("Failed to serialize the profile")?;
+            output.push_str(&format!(
+                "\n<details>\n<summary>Profile: {}</summary>\n\n```toml\n{}```\n</details>\n",
+                profile, resolved
+            ));
+        }
+        Ok(output)
+    }
+
+    fn fairness_table(&self) -> String {
+        let measured: Vec<(&ResultEntry, &Fairness)> = self
+            .results
+            .iter()
+            .filter_map(|e| e.fairness.as_ref().map(|f| (e, f)))
+            .collect();
+        if measured.is_empty() {
+            return String::new();
+        }
+
+        let mut table = vec![vec![
+            "Server".to_string(),
+            "Client".to_string(),
+            "Test".to_string(),
+            "Payload".to_string(),
+            "Streams".to_string(),
+            "Jain".to_string(),
+            "Min".to_string(),
+            "Max".to_string(),
+            "Stalled".to_string(),
+        ]];
+        for (entry, fairness) in measured {
+            table.push(vec![
+                entry.server.clone(),
+                entry.client.clone(),
+                entry.options.section(),
+                util::format_size(entry.options.payload_size),
+                fairness.streams.to_string(),
+                format!("{:.3}", fairness.jain),
+                format!("{:.2} MiB/s", fairness.min),
+                format!("{:.2} MiB/s", fairness.max),
+                fairness.stalled.to_string(),
+            ]);
+        }
+        format!(
+            "\n## Stream fairness\n\nJain's index is 1 when every stream gets the same throughput, stalled streams moved no data.\n\n{}",
+            benchmark::format_table(&table)
+        )
+    }
+
+    fn repeats_table(&self) -> String {
+        let repeated: Vec<(&ResultEntry, &Repeats)> = self
+            .results
+            .iter()
+            .filter_map(|e| e.repeats.as_ref().map(|r| (e, r)))
+            .collect();
+        if repeated.is_empty() {
+            return String::new();
+        }
+
+        let mut table = vec![vec![
+            "Server".to_string(),
+            "Client".to_string(),
+            "Test".to_string(),
+            "Payload".to_string(),
+            "Succeeded".to_string(),
+            "Spread".to_string(),
+        ]];
+        for (entry, repeats) in repeated {
+            table.push(vec![
+                entry.server.clone(),
+                entry.client.clone(),
+                entry.options.section(),
+                util::format_size(entry.options.payload_size),
+                format!("{}/{}", repeats.succeeded, repeats.total),
+                repeats.spread.to_string(),
+            ]);
+        }
+        format!(
+            "\n## Repeats\n\nResults are averaged over the runs that succeeded, the spread is the difference between the fastest and the slowest of them.\n\n{}",
+            benchmark::format_table(&table)
+        )
+    }
+
+    fn noise_table(&self) -> String {
+        let noisy: Vec<(&ResultEntry, f64)> = self
+            .results
+            .iter()
+            .filter_map(|e| e.noise.map(|n| (e, n)))
+            .filter(|&(_, noise)| noise > self.settings.max_noise)
+            .collect();
+        if noisy.is_empty() {
+            return String::new();
+        }
+
+        let mut table = vec![vec![
+            "Server".to_string(),
+            "Client".to_string(),
+            "Test".to_string(),
+            "Payload".to_string(),
+            "Other processes".to_string(),
+        ]];
+        for (entry, noise) in noisy {
+            table.push(vec![
+                entry.server.clone(),
+                entry.client.clone(),
+                entry.options.section(),
+                util::format_size(entry.options.payload_size),
+                format!("{:.0}% CPU", noise * 100.0),
+            ]);
+        }
+        format!(
+            "\n## Noisy measurements\n\nOther processes used more than {:.0}% of the CPU time while these pairs were measured, so their results may be too low.\n\n{}",
+            self.settings.max_noise * 100.0,
+            benchmark::format_table(&table)
+        )
+    }
+
+    pub fn find(
+        &self,
+        server: &str,
+        client: &str,
+        options: &RunOptions,
+    ) -> Option<&BenchmarkResult> {
+        self.results
+            .iter()
+            .find(|e| e.server == server && e.client == client && e.options == *options)
+            .map(|e| &e.result)
+    }
+}
+
+// results are written to the run directory after every pair so an interrupted run can resume
+pub struct Checkpoint {
+    pub dir: PathBuf,
+    pub file: ResultsFile,
+}
+
+impl Checkpoint {
+    const FILE: &'static str = "checkpoint.json";
+
+    pub fn create(cpu: String, settings: BenchmarkSettings) -> Result<Self> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let runs = util::runs_dir();
+        std::fs::create_dir_all(&runs)
+            .with_context(|| format!("Failed to create {}", runs.display()))?;
+        // the pid keeps concurrent runs apart, the counter runs started by the same process
+        let mut n = 0;
+        let dir = loop {
+            let mut name = format!("run-{}-{}", started, std::process::id());
+            if n > 0 {
+                name.push_str(&format!("-{}", n));
+            }
+            let dir = runs.join(name);
+            match std::fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to create {}", dir.display()))
+                }
+            }
+        };
+        let mut file = ResultsFile::new(&BenchmarkResults::new(), cpu, settings, None);
+        file.partial = true;
+        Ok(Self { dir, file })
+    }
+
+    pub fn load(dir: &Path) -> Result<Self> {
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            file: ResultsFile::load(&dir.join(Self::FILE))?,
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = self.dir.join(Self::FILE);
+        let tmp = path.with_extension("json.tmp");
+        self.file.save(&tmp)?;
+        std::fs::rename(&tmp, &path).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn record(&mut self, entry: ResultEntry) -> Result<()> {
+        self.file.results.push(entry);
+        self.save()
+    }
+
+    pub fn record_system(&mut self, system: SystemInfo) -> Result<()> {
+        self.file.system = Some(system);
+        self.save()
+    }
+
+    pub fn record_baseline(
+        &mut self,
+        client: &str,
+        options: RunOptions,
+        shape: LoadShape,
+        bandwidth: Bandwidth,
+    ) -> Result<()> {
+        let baseline = self
+            .file
+            .baseline
+            .get_or_insert_with(BaselineResults::default);
+        baseline
+            .bandwidths
+            .push((client.to_string(), options, bandwidth));
+        if baseline.shape(&shape).is_none() {
+            baseline.shapes.push((shape, bandwidth));
+        }
+        self.save()
+    }
+
+    pub fn finish(&mut self, completed: bool) -> Result<()> {
+        self.file.partial = !completed;
+        self.save()
+    }
+}
+
+fn change(before: f64, after: f64) -> String {
+    if before <= 0.0 {
+        return "-".to_string();
+    }
+    format!("{:+.1}%", (after - before) / before * 100.0)
+}
+
+pub fn compare(before: &ResultsFile, after: &ResultsFile) -> String {
+    let mut output = String::new();
+
+    if before.cpu == after.cpu {
+        output.push_str(&format!("CPU: {}\n\n", after.cpu));
+    } else {
+        output.push_str(&format!("CPU: {} → {}\n\n", before.cpu, after.cpu));
+    }
+    output.push_str("Throughput: upstream / downstream, change relative to the first file\n");
+
+    let mut sections: Vec<String> = Vec::new();
+    for entry in &after.results {
+        if !sections.contains(&entry.options.section()) {
+            sections.push(entry.options.section());
+        }
+    }
+
+    for section in &sections {
+        output.push_str(&format!("\n### {}\n\n", section));
+        let mut table = vec![vec![
+            "Server".to_string(),
+            "Client".to_string(),
+            "Payload".to_string(),
+            "Before".to_string(),
+            "After".to_string(),
+            "Change".to_string(),
+        ]];
+
+        for entry in after
+            .results
+            .iter()
+            .filter(|e| e.options.section() == *section)
+        {
+            let previous = before.find(&entry.server, &entry.client, &entry.options);
+            let delta = match (previous, &entry.result) {
+                (Some(BenchmarkResult::Success(old)), BenchmarkResult::Success(new)) => format!(
+                    "{} / {}",
+                    change(old.up, new.up),
+                    change(old.down, new.down)
+                ),
+                _ => "-".to_string(),
+            };
+            table.push(vec![
+                entry.server.clone(),
+                entry.client.clone(),
+                util::format_size(entry.options.payload_size),
+                previous.map_or_else(|| "-".to_string(), |r| r.to_string()),
+                entry.result.to_string(),
+                delta,
+            ]);
+        }
+
+        output.push_str(&benchmark::format_table(&table));
+    }
+
+    output
+}
@@ -0,0 +1,228 @@
+use crate::config::RunConfig;
+use crate::impair::Impairment;
+use crate::placement::Placement;
+use crate::report::{Checkpoint, ResultsFile};
+use crate::server::{self, OptionSets};
+use crate::structure::{
+    BenchmarkSettings, Compression, PayloadMode, TrafficPattern, Transport, WispClient, WispServer,
+};
+use crate::{benchmark, echo, util};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Builds and runs a benchmark of every server against every client.
+///
+/// Built-in implementations are selected by name with [`servers`](Self::servers) and
+/// [`clients`](Self::clients). Your own implementations are added with [`server`](Self::server)
+/// and [`client`](Self::client); once one is added, built-in implementations on that side are
+/// only included when they are selected by name.
+pub struct BenchmarkRunner {
+    settings: BenchmarkSettings,
+    servers: Vec<Box<dyn WispServer>>,
+    clients: Vec<Box<dyn WispClient>>,
+    server_options: Vec<(String, String, String)>,
+    base_dir: Option<PathBuf>,
+    checkpoint: Option<Checkpoint>,
+}
+
+impl Default for BenchmarkRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BenchmarkRunner {
+    /// A runner with the same defaults as `wispmark run`.
+    pub fn new() -> Self {
+        let settings = RunConfig::default().settings(OptionSets::new());
+        Self::with_settings(settings.expect("the default run config is valid"))
+    }
+
+    pub fn with_settings(settings: BenchmarkSettings) -> Self {
+        Self {
+            settings,
+            servers: Vec::new(),
+            clients: Vec::new(),
+            server_options: Vec::new(),
+            base_dir: None,
+            checkpoint: None,
+        }
+    }
+
+    /// Records into an existing checkpoint, skipping the pairs it already has results for.
+    /// The settings are taken from the checkpoint.
+    pub fn from_checkpoint(checkpoint: Checkpoint) -> Self {
+        let mut runner = Self::with_settings(checkpoint.file.settings.clone());
+        runner.checkpoint = Some(checkpoint);
+        runner
+    }
+
+    pub fn settings(&self) -> &BenchmarkSettings {
+        &self.settings
+    }
+
+    /// Seconds each pair is measured for.
+    pub fn duration(mut self, seconds: u64) -> Self {
+        self.settings.duration = seconds;
+        self
+    }
+
+    pub fn repeats(mut self, repeats: usize) -> Self {
+        self.settings.repeats = repeats.max(1);
+        self
+    }
+
+    /// Parallel install jobs for implementations that are missing.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.settings.jobs = jobs;
+        self
+    }
+
+    pub fn servers<S: Into<String>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.settings.servers = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn clients<S: Into<String>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.settings.clients = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn server(mut self, server: impl WispServer + 'static) -> Self {
+        self.servers.push(Box::new(server));
+        self
+    }
+
+    pub fn client(mut self, client: impl WispClient + 'static) -> Self {
+        self.clients.push(Box::new(client));
+        self
+    }
+
+    /// Streams per client instance for the built-in clients.
+    pub fn streams(mut self, streams: impl IntoIterator<Item = usize>) -> Self {
+        self.settings.streams = streams.into_iter().collect();
+        self
+    }
+
+    /// Client instances for the built-in clients.
+    pub fn instances(mut self, instances: impl IntoIterator<Item = usize>) -> Self {
+        self.settings.instances = instances.into_iter().collect();
+        self
+    }
+
+    pub fn payload_sizes(mut self, sizes: impl IntoIterator<Item = usize>) -> Self {
+        self.settings.payload_sizes = sizes.into_iter().collect();
+        self
+    }
+
+    pub fn patterns(mut self, patterns: impl IntoIterator<Item = TrafficPattern>) -> Self {
+        self.settings.patterns = patterns.into_iter().collect();
+        self
+    }
+
+    pub fn transports(mut self, transports: impl IntoIterator<Item = Transport>) -> Self {
+        self.settings.transports = transports.into_iter().collect();
+        self
+    }
+
+    pub fn compressions(mut self, compressions: impl IntoIterator<Item = Compression>) -> Self {
+        self.settings.compressions = compressions.into_iter().collect();
+        self
+    }
+
+    pub fn payload_modes(mut self, modes: impl IntoIterator<Item = PayloadMode>) -> Self {
+        self.settings.payload_modes = modes.into_iter().collect();
+        self
+    }
+
+    /// Sets a built-in server option, like `--server-option <server>.<key>=<value>`.
+    pub fn server_option(mut self, server: &str, key: &str, value: &str) -> Self {
+        self.server_options
+            .push((server.to_string(), key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Measures per-stream throughput with an instrumented target.
+    pub fn fairness(mut self, fairness: bool) -> Self {
+        self.settings.fairness = fairness;
+        self
+    }
+
+    /// Pins the built-in servers, clients and echo target to CPUs and limits the server.
+    pub fn placement(mut self, placement: Placement) -> Self {
+        self.settings.placement = placement;
+        self
+    }
+
+    /// Flags pairs whose measurement saw other processes use more than `max_noise` of the
+    /// CPU time, and warns before the run when the machine is already that busy.
+    pub fn max_noise(mut self, max_noise: f64) -> Self {
+        self.settings.max_noise = max_noise;
+        self
+    }
+
+    /// Refuses to start on a busy machine instead of warning.
+    pub fn abort_when_busy(mut self, abort: bool) -> Self {
+        self.settings.abort_when_busy = abort;
+        self
+    }
+
+    /// Measures raw TCP throughput to the target with the shape of every client, for the
+    /// "% of baseline" columns.
+    pub fn baseline(mut self, baseline: bool) -> Self {
+        self.settings.baseline = baseline;
+        self
+    }
+
+    /// Payloads in flight on every baseline connection, instead of the client's own depth.
+    pub fn baseline_depth(mut self, depth: usize) -> Self {
+        self.settings.baseline_depth = Some(depth);
+        self
+    }
+
+    pub fn impairment(mut self, impairment: Impairment) -> Self {
+        self.settings.impairment = Some(impairment);
+        self
+    }
+
+    /// Directory implementations are installed to, the current directory by default.
+    pub fn base_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.base_dir = Some(dir.into());
+        self
+    }
+
+    /// Runs every pair and returns the results. They are marked as partial when the run was
+    /// interrupted with Ctrl-C.
+    pub async fn run(mut self) -> Result<ResultsFile> {
+        if let Some(dir) = self.base_dir.take() {
+            util::set_base_dir(dir)?;
+        }
+        util::write_wispjs_files(&util::base())?;
+        util::sudo()?;
+        echo::get_echo().await?;
+
+        if !self.server_options.is_empty() {
+            self.settings.server_options =
+                server::option_sets(&HashMap::new(), &self.server_options, &[])?;
+        }
+
+        let mut servers = self.servers;
+        if servers.is_empty() || !self.settings.servers.is_empty() {
+            servers.splice(0..0, benchmark::servers(&self.settings));
+        }
+        let mut clients = self.clients;
+        if clients.is_empty() || !self.settings.clients.is_empty() {
+            clients.splice(0..0, benchmark::clients(&self.settings));
+        }
+
+        let mut checkpoint = match self.checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => Checkpoint::create(util::get_cpu_info()?, self.settings.clone())?,
+        };
+        let completed =
+            benchmark::run_pairs(&self.settings, servers, clients, &mut checkpoint).await?;
+        checkpoint.finish(completed)?;
+        Ok(checkpoint.file)
+    }
+}
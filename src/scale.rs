@@ -0,0 +1,390 @@
+use crate::config::Config;
+use crate::placement::Placement;
+use crate::structure::{
+    BenchmarkResult, BenchmarkSettings, RunOptions, TrafficPattern, Transport, WispClient,
+    WispServer,
+};
+use crate::system::SystemInfo;
+use crate::{benchmark, client, echo, manage, placement, server, tls, util};
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug)]
+pub struct ScaleArgs {
+    #[arg(long, value_delimiter = ',')]
+    pub servers: Vec<String>,
+
+    #[arg(long, default_value = "wisp-js")]
+    pub client: String,
+
+    #[arg(long, value_delimiter = ',', default_value = "1,10,100,1000,5000")]
+    pub streams: Vec<usize>,
+
+    #[arg(long, value_delimiter = ',', default_value = "1,2,4,8,16")]
+    pub connections: Vec<usize>,
+
+    #[arg(long, default_value_t = 0.05)]
+    pub plateau: f64,
+
+    #[arg(long)]
+    pub duration: Option<u64>,
+
+    #[arg(long, value_enum)]
+    pub pattern: Option<TrafficPattern>,
+
+    #[arg(long, value_enum)]
+    pub transport: Option<Transport>,
+
+    #[arg(long, value_parser = util::parse_size)]
+    pub payload_size: Option<usize>,
+
+    #[arg(long, default_value = "wispmark-scale.md")]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Point {
+    pub connections: usize,
+    pub streams: usize,
+}
+
+impl Point {
+    pub fn total(&self) -> usize {
+        self.connections * self.streams
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Step {
+    #[serde(flatten)]
+    pub point: Point,
+    pub result: BenchmarkResult,
+}
+
+impl Step {
+    fn throughput(&self) -> Option<f64> {
+        match &self.result {
+            BenchmarkResult::Success(bw) => Some(bw.up + bw.down),
+            BenchmarkResult::Failed(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Curve {
+    pub server: String,
+    pub steps: Vec<Step>,
+    pub knee: Option<Point>,
+    /// The first step that gained less than the plateau threshold over the best step before it.
+    #[serde(default)]
+    pub plateau_at: Option<Point>,
+    pub fails_at: Option<Point>,
+}
+
+impl Curve {
+    fn peak(&self) -> Option<&Step> {
+        self.steps
+            .iter()
+            .filter(|s| s.throughput().is_some())
+            .max_by(|a, b| a.throughput().partial_cmp(&b.throughput()).unwrap())
+    }
+
+    // the knee is the first step within the plateau threshold of the peak
+    fn finish(&mut self, plateau: f64) {
+        let peak = self.peak().and_then(Step::throughput).unwrap_or_default();
+        self.knee = self
+            .steps
+            .iter()
+            .find(|s| s.throughput().is_some_and(|t| t >= peak * (1.0 - plateau)))
+            .map(|s| s.point);
+        self.fails_at = self
+            .steps
+            .iter()
+            .find(|s| s.throughput().is_none())
+            .map(|s| s.point);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ScaleResults {
+    pub system: SystemInfo,
+    pub client: String,
+    pub options: RunOptions,
+    pub duration: u64,
+    pub plateau: f64,
+    #[serde(default, skip_serializing_if = "Placement::is_empty")]
+    pub placement: Placement,
+    pub partial: bool,
+    pub curves: Vec<Curve>,
+}
+
+pub async fn run(args: ScaleArgs, config: &Config) -> Result<()> {
+    let mut settings = config
+        .run
+        .settings(server::option_sets(&config.server_options, &[], &[])?)?;
+    if let Some(duration) = args.duration {
+        settings.duration = duration;
+    }
+    settings.repeats = 1;
+    let options = RunOptions {
+        payload_size: args.payload_size.unwrap_or(settings.payload_sizes[0]),
+        pattern: args.pattern.unwrap_or(settings.patterns[0]),
+        transport: args.transport.unwrap_or(settings.transports[0]),
+        compression: settings.compressions[0],
+        payload_mode: settings.payload_modes[0],
+    };
+
+    let servers: Vec<Box<dyn WispServer>> = server::get_implementations(&settings.server_options)
+        .into_iter()
+        .filter(|s| util::name_matches(s.name(), &args.servers))
+        .collect();
+    let client_at = |point: Point| -> Option<Box<dyn WispClient>> {
+        client::get_matrix(&[point.streams], &[point.connections])
+            .into_iter()
+            .find(|c| util::name_matches(c.name(), std::slice::from_ref(&args.client)))
+    };
+    let Some(first) = client_at(Point {
+        connections: 1,
+        streams: 1,
+    }) else {
+        bail!(
+            "Unknown client {}, expected wisp-js or wisp-mux",
+            args.client
+        );
+    };
+    if servers.is_empty() {
+        bail!("No server matches the selected implementations");
+    }
+    if !first.supports(&options) {
+        bail!("{} doesn't support {}", args.client, options.section());
+    }
+
+    util::sudo()?;
+    let _placement = placement::activate(&settings.placement)?;
+    if !settings.placement.is_empty() {
+        println!("Placement: {}", settings.placement);
+    }
+    echo::get_echo().await?;
+    if options.transport == Transport::Wss {
+        tls::generate_certificate()?;
+    }
+    let clients = vec![first];
+    let mut system = SystemInfo::collect();
+    let failed = manage::install_missing(&servers, &clients, settings.jobs);
+    if !failed.is_empty() {
+        let failed: Vec<String> = failed.into_iter().collect();
+        bail!("Failed to install {}", failed.join(", "));
+    }
+    system.record_versions(&servers, &clients);
+    let log_dir = util::log_dir();
+    tokio::fs::create_dir_all(&log_dir).await?;
+
+    let mut results = ScaleResults {
+        system,
+        client: args.client.clone(),
+        options,
+        duration: settings.duration,
+        plateau: args.plateau,
+        placement: settings.placement.clone(),
+        partial: true,
+        curves: Vec::new(),
+    };
+    let target = echo::run_target(options.pattern, settings.fairness)?;
+    util::wait_for_tcp(util::ECHO_PORT, util::SERVER_TIMEOUT).await?;
+
+    let ramp = Ramp {
+        settings: &settings,
+        options: &options,
+        log_dir: &log_dir,
+        plateau: args.plateau,
+        client_at,
+    };
+    let measure = async {
+        for server in &servers {
+            results.curves.push(Curve {
+                server: server.name().to_string(),
+                steps: Vec::new(),
+                knee: None,
+                plateau_at: None,
+                fails_at: None,
+            });
+            let curve = results.curves.last_mut().unwrap();
+
+            // streams per connection first, then more connections at the best stream count
+            let streams = args.streams.iter().map(|&streams| Point {
+                connections: 1,
+                streams,
+            });
+            ramp.run(server.as_ref(), streams, curve).await;
+            let Some(best) = curve.peak().map(|s| s.point.streams) else {
+                continue;
+            };
+            let connections = args
+                .connections
+                .iter()
+                .filter(|&&c| c > 1)
+                .map(|&connections| Point {
+                    connections,
+                    streams: best,
+                });
+            ramp.run(server.as_ref(), connections, curve).await;
+        }
+    };
+    let completed = tokio::select! {
+        _ = measure => true,
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!("\nInterrupted, writing the steps measured so far...");
+            if let Err(e) = util::kill(util::WISP_PORT) {
+                eprintln!("Warning: Failed to stop the server: {}", e);
+            }
+            false
+        }
+    };
+    drop(target);
+    results.partial = !completed;
+    for curve in &mut results.curves {
+        curve.finish(args.plateau);
+    }
+
+    let markdown = markdown(&results);
+    println!("{}", markdown);
+    tokio::fs::write(&args.output, &markdown).await?;
+    println!("\nMarkdown results written to: {}", args.output.display());
+    let json_output = args.output.with_extension("json");
+    let json = serde_json::to_string_pretty(&results).context("Failed to serialize results")?;
+    tokio::fs::write(&json_output, json)
+        .await
+        .with_context(|| format!("Failed to write {}", json_output.display()))?;
+    println!("JSON results written to: {}", json_output.display());
+    Ok(())
+}
+
+struct Ramp<'a, F> {
+    settings: &'a BenchmarkSettings,
+    options: &'a RunOptions,
+    log_dir: &'a Path,
+    plateau: f64,
+    client_at: F,
+}
+
+impl<F: Fn(Point) -> Option<Box<dyn WispClient>>> Ramp<'_, F> {
+    // notes where a step first gains less than the plateau threshold, but only stops at the
+    // first failure so the curve still shows where the server breaks
+    async fn run(
+        &self,
+        server: &dyn WispServer,
+        points: impl Iterator<Item = Point>,
+        curve: &mut Curve,
+    ) {
+        let mut best = curve.peak().and_then(Step::throughput);
+        for point in points {
+            let Some(client) = (self.client_at)(point) else {
+                break;
+            };
+            println!(
+                "Testing {} with {} connection(s) × {} stream(s)",
+                curve.server, point.connections, point.streams
+            );
+            let name = format!(
+                "{}_{}x{}",
+                util::log_name(&curve.server),
+                point.connections,
+                point.streams
+            );
+            let (result, _, _) = benchmark::single(
+                server,
+                client.as_ref(),
+                self.settings,
+                self.options,
+                &self.log_dir.join(format!("SCALE_SERVER_{}.log", name)),
+                &self.log_dir.join(format!("SCALE_CLIENT_{}.log", name)),
+                self.settings.duration,
+            )
+            .await;
+            let step = Step { point, result };
+            let throughput = step.throughput();
+            curve.steps.push(step);
+
+            let Some(t) = throughput else {
+                break;
+            };
+            if best.is_some_and(|b| t < b * (1.0 + self.plateau)) && curve.plateau_at.is_none() {
+                curve.plateau_at = Some(point);
+            }
+            best = Some(best.map_or(t, |b| b.max(t)));
+        }
+    }
+}
+
+pub fn markdown(results: &ScaleResults) -> String {
+    let mut output = String::new();
+    if results.partial {
+        output.push_str(
+            "**Partial results: the run was interrupted before every step finished.**\n\n",
+        );
+    }
+    output.push_str(&results.system.markdown());
+    output.push_str(&format!(
+        "Client: {}, {}, {} payloads, {}s per step\n",
+        results.client,
+        results.options.section(),
+        util::format_size(results.options.payload_size),
+        results.duration
+    ));
+    if !results.placement.is_empty() {
+        output.push_str(&format!("Placement: {}\n", results.placement));
+    }
+    output.push_str(&format!(
+        "The knee is the first step within {:.0}% of the peak throughput.\n",
+        results.plateau * 100.0
+    ));
+
+    let point = |p: Option<Point>| {
+        p.map_or_else(
+            || "-".to_string(),
+            |p| format!("{} ({}×{})", p.total(), p.connections, p.streams),
+        )
+    };
+    let mut summary = vec![vec![
+        "Server".to_string(),
+        "Peak".to_string(),
+        "Knee (streams)".to_string(),
+        "Plateaus at (streams)".to_string(),
+        "Fails at (streams)".to_string(),
+    ]];
+    for curve in &results.curves {
+        summary.push(vec![
+            curve.server.clone(),
+            curve
+                .peak()
+                .map_or_else(|| "-".to_string(), |s| s.result.to_string()),
+            point(curve.knee),
+            point(curve.plateau_at),
+            point(curve.fails_at),
+        ]);
+    }
+    output.push_str("\n## Summary\n\n");
+    output.push_str(&benchmark::format_table(&summary));
+
+    for curve in &results.curves {
+        output.push_str(&format!("\n## {}\n\n", curve.server));
+        let mut table = vec![vec![
+            "Connections".to_string(),
+            "Streams per connection".to_string(),
+            "Total streams".to_string(),
+            "Throughput".to_string(),
+        ]];
+        for step in &curve.steps {
+            table.push(vec![
+                step.point.connections.to_string(),
+                step.point.streams.to_string(),
+                step.point.total().to_string(),
+                step.result.to_string(),
+            ]);
+        }
+        output.push_str(&benchmark::format_table(&table));
+    }
+    output
+}
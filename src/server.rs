@@ -1,48 +1,102 @@
-use crate::structure::{EpoxyServer, WispGo, WispJS, WispPy, WispServer};
-use crate::util;
-use anyhow::{Context, Result};
-use std::path::PathBuf;
-use std::process::{Child, Command};
+use crate::placement::Role;
+use crate::process::ProcessHandle;
+use crate::structure::{
+    Compression, EpoxyServer, RunHandle, RunOptions, ServerOptions, Transport, WispGo, WispJS,
+    WispPy, WispServer,
+};
+use crate::{offline, tls, util};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+const WISP_PY_REPO: &str = "https://github.com/MercuryWorkshop/wisp-server-python";
+const GO_WISP_REPO: &str = "https://github.com/TheFalloutOf76/go-wisp";
+const INSTALLED_MARKER: &str = ".wispmark-installed";
 
 impl WispJS {
-    pub fn new() -> Self {
+    pub fn new(options: ServerOptions) -> Self {
         Self {
             path: util::base().join("server/js"),
+            name: option_label("wisp-js", &options),
+            options,
         }
     }
 }
 
+#[async_trait]
 impl WispServer for WispJS {
     fn name(&self) -> &str {
-        "wisp-js"
+        &self.name
     }
 
-    fn install(&self) -> Result<()> {
+    fn install(&self, log_file: &Path) -> Result<()> {
         std::fs::create_dir_all(&self.path)?;
-        Command::new("npm")
-            .arg("i")
-            .current_dir(&self.path)
-            .status()
-            .context("Failed to run npm install")?;
-        Ok(())
+        util::run_logged(
+            util::npm_install().current_dir(&self.path),
+            log_file,
+            "run npm install",
+        )
+    }
+
+    fn fetch(&self, mirror: &Path, log_file: &Path) -> Result<()> {
+        offline::cache_npm(&self.path, mirror, log_file)
     }
 
     fn check_install(&self) -> bool {
-        self.path.join("node_modules").exists()
+        self.path
+            .join("node_modules/@mercuryworkshop/wisp-js")
+            .exists()
+    }
+
+    fn requirements(&self) -> Vec<&str> {
+        vec!["node", "npm"]
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn version(&self) -> Option<String> {
+        util::npm_version(&self.path, "@mercuryworkshop/wisp-js")
+    }
+
+    fn update(&self, _reference: Option<&str>, log_file: &Path) -> Result<()> {
+        util::npm_update(&self.path, log_file)
     }
 
-    fn run(&self, port: u16, log_file: &PathBuf) -> Result<Child> {
-        util::run(
+    fn clean(&self) -> Result<()> {
+        // server.mjs and package.json are written by wispmark itself
+        util::remove_dir(&self.path.join("node_modules"))?;
+        let lockfile = self.path.join("package-lock.json");
+        if lockfile.exists() {
+            std::fs::remove_file(lockfile)?;
+        }
+        Ok(())
+    }
+
+    async fn start(
+        &self,
+        port: u16,
+        _options: &RunOptions,
+        log_file: &Path,
+    ) -> Result<Box<dyn RunHandle>> {
+        let options = serde_json::to_string(&self.options)?;
+        let child = util::run(
+            Role::Server,
             "node",
-            &["server.mjs", &port.to_string()],
+            &["server.mjs", &port.to_string(), &options],
             Some(&self.path),
             log_file,
-        )
+        )?;
+        Ok(Box::new(ProcessHandle::server(child, port, false)))
     }
 }
 
 impl WispPy {
-    pub fn new(python: &str) -> Self {
+    pub fn new(python: &str, options: ServerOptions) -> Self {
         let path = util::base().join("server/python");
         let repo = path.join("wisp-server-python");
         let venv = path.join(format!(".venv_{}", python));
@@ -57,110 +111,206 @@ impl WispPy {
             repo,
             venv,
             python: python.to_string(),
-            name,
+            name: option_label(&name, &options),
+            options,
         }
     }
 }
 
+#[async_trait]
 impl WispServer for WispPy {
     fn name(&self) -> &str {
         &self.name
     }
 
-    fn install(&self) -> Result<()> {
+    fn install(&self, log_file: &Path) -> Result<()> {
         std::fs::create_dir_all(&self.path)?;
-        if !self.repo.exists() {
-            Command::new("git")
-                .args(&[
-                    "clone",
-                    "https://github.com/MercuryWorkshop/wisp-server-python",
-                ])
-                .arg(&self.repo)
-                .status()
-                .context("Failed to clone wisp-server-python")?;
-        }
-        Command::new(&self.python)
-            .args(&["-m", "venv"])
-            .arg(&self.venv)
-            .current_dir(&self.repo)
-            .status()
-            .context("Failed to create venv")?;
+        util::clone(WISP_PY_REPO, &self.repo, log_file)?;
+        util::run_logged(
+            Command::new(&self.python)
+                .args(["-m", "venv"])
+                .arg(&self.venv)
+                .current_dir(&self.repo),
+            log_file,
+            "create venv",
+        )?;
         let activate_cmd = format!(
-            "source {}/bin/activate; pip3 install -e .",
-            self.venv.display()
+            "source {}/bin/activate; pip3 install {} -e .",
+            self.venv.display(),
+            util::pip_flags()
         );
-        Command::new("bash")
-            .args(&["-c", &activate_cmd])
-            .current_dir(&self.repo)
-            .status()
-            .context("Failed to install Python package")?;
+        util::run_logged(
+            Command::new("bash")
+                .args(["-c", &activate_cmd])
+                .current_dir(&self.repo),
+            log_file,
+            "install Python package",
+        )?;
+        std::fs::write(self.venv.join(INSTALLED_MARKER), "")?;
         Ok(())
     }
 
+    fn fetch(&self, mirror: &Path, log_file: &Path) -> Result<()> {
+        let repo = offline::fetch_repo(WISP_PY_REPO, mirror, log_file)?;
+        offline::download_wheels(&self.python, &repo, mirror, log_file)
+    }
+
     fn check_install(&self) -> bool {
-        self.venv.exists()
+        self.venv.join(INSTALLED_MARKER).exists()
     }
 
-    fn run(&self, port: u16, log_file: &PathBuf) -> Result<Child> {
-        let cmd =
-            format!(
-            "source {}/bin/activate; python3 -m wisp.server --port={} --allow-loopback 2>&1 >'{}'",
-            self.venv.display(), port, log_file.display()
-        );
-        Command::new("bash")
-            .args(&["-c", &cmd])
-            .current_dir(&self.repo)
-            .spawn()
-            .context("Failed to spawn Python server")
+    fn requirements(&self) -> Vec<&str> {
+        vec!["git", "bash", &self.python]
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn version(&self) -> Option<String> {
+        util::git_version(&self.repo)
+    }
+
+    fn update(&self, reference: Option<&str>, log_file: &Path) -> Result<()> {
+        util::git_update(&self.repo, reference, log_file)?;
+        self.install(log_file)
+    }
+
+    fn clean(&self) -> Result<()> {
+        util::remove_dir(&self.venv)?;
+        util::remove_dir(&self.repo)
+    }
+
+    async fn start(
+        &self,
+        port: u16,
+        _options: &RunOptions,
+        log_file: &Path,
+    ) -> Result<Box<dyn RunHandle>> {
+        let mut args = vec![
+            "-m".to_string(),
+            "wisp.server".to_string(),
+            format!("--port={}", port),
+            "--allow-loopback".to_string(),
+        ];
+        for (key, value) in &self.options {
+            match value {
+                Value::Bool(true) => args.push(format!("--{}", key)),
+                Value::Bool(false) | Value::Null => {}
+                Value::String(s) => args.push(format!("--{}={}", key, s)),
+                other => args.push(format!("--{}={}", key, other)),
+            }
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let python = self.venv.join("bin/python3");
+        let child = util::run(
+            Role::Server,
+            &python.to_string_lossy(),
+            &args,
+            Some(&self.repo),
+            log_file,
+        )?;
+        Ok(Box::new(ProcessHandle::server(child, port, false)))
     }
 }
 
 impl EpoxyServer {
-    pub fn new(threading: &str) -> Self {
-        let path = util::base().join("server/rust");
+    pub fn new(threading: &str, options: ServerOptions) -> Self {
+        let path = util::epoxy_dir();
         let epoxy_src = path.join("server");
         Self {
             path,
             epoxy_src,
             threading: threading.to_string(),
-            name: format!("epoxy-server ({})", threading),
+            name: option_label(&format!("epoxy-server ({})", threading), &options),
+            options,
         }
     }
 }
 
+#[async_trait]
 impl WispServer for EpoxyServer {
     fn name(&self) -> &str {
         &self.name
     }
 
-    fn install(&self) -> Result<()> {
-        if !self.path.exists() {
-            Command::new("git")
-                .args(&["clone", "https://github.com/MercuryWorkshop/epoxy-tls"])
-                .arg(&self.path)
-                .status()
-                .context("Failed to clone epoxy-tls")?;
-        }
-        Command::new("cargo")
-            .args(&["build", "--release"])
-            .current_dir(&self.epoxy_src)
-            .status()
-            .context("Failed to build Rust server")?;
-        Ok(())
+    fn install(&self, log_file: &Path) -> Result<()> {
+        util::clone(util::EPOXY_REPO, &self.path, log_file)?;
+        util::run_logged(
+            util::cargo(&["build", "--release"]).current_dir(&self.epoxy_src),
+            log_file,
+            "build Rust server",
+        )
+    }
+
+    fn fetch(&self, mirror: &Path, log_file: &Path) -> Result<()> {
+        let repo = offline::fetch_repo(util::EPOXY_REPO, mirror, log_file)?;
+        offline::vendor_cargo(&repo, log_file)
     }
 
     fn check_install(&self) -> bool {
         self.path.join("target/release/epoxy-server").exists()
     }
 
-    fn run(&self, port: u16, log_file: &PathBuf) -> Result<Child> {
-        let config = format!(
-            "[server]\nbind = [\"tcp\", \"127.0.0.1:{}\"]\nruntime = \"{}\"",
-            port, self.threading
-        );
+    fn requirements(&self) -> Vec<&str> {
+        vec!["git", "cargo", "rustc", "gcc"]
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn version(&self) -> Option<String> {
+        util::git_version(&self.path)
+    }
+
+    fn update(&self, reference: Option<&str>, log_file: &Path) -> Result<()> {
+        util::git_update(&self.path, reference, log_file)?;
+        self.install(log_file)
+    }
+
+    fn clean(&self) -> Result<()> {
+        util::remove_dir(&self.path)
+    }
+
+    fn native_tls(&self) -> bool {
+        true
+    }
+
+    async fn prepare(&self, port: u16, options: &RunOptions) -> Result<()> {
+        let mut server = serde_json::json!({
+            "bind": ["tcp", format!("127.0.0.1:{}", port)],
+            "runtime": self.threading,
+        });
+        if options.transport == Transport::Wss {
+            let certificate = tls::certificate();
+            server["bind"][0] = "tlstcp".into();
+            server["tls_keypair"] = serde_json::json!([certificate.cert, certificate.key]);
+        }
+        let mut config = serde_json::json!({ "server": server });
+        // bare keys belong to [server], dotted ones pick their own section
+        for (key, value) in &self.options {
+            let path = if key.contains('.') {
+                key.clone()
+            } else {
+                format!("server.{}", key)
+            };
+            set_option(&mut config, &path, value.clone());
+        }
+        let config = toml::to_string(&config).context("Failed to serialize epoxy config")?;
+        tokio::fs::write(self.epoxy_src.join("config.toml"), config).await?;
+        Ok(())
+    }
+
+    async fn start(
+        &self,
+        port: u16,
+        options: &RunOptions,
+        log_file: &Path,
+    ) -> Result<Box<dyn RunHandle>> {
         let config_path = self.epoxy_src.join("config.toml");
-        std::fs::write(&config_path, config)?;
-        util::run(
+        let child = util::run(
+            Role::Server,
             self.path
                 .join("target/release/epoxy-server")
                 .to_str()
@@ -168,50 +318,88 @@ impl WispServer for EpoxyServer {
             &[config_path.to_str().unwrap()],
             Some(&self.epoxy_src),
             log_file,
-        )
+        )?;
+        let tls = options.transport == Transport::Wss;
+        Ok(Box::new(ProcessHandle::server(child, port, tls)))
     }
 }
 
 impl WispGo {
-    pub fn new() -> Self {
+    pub fn new(options: ServerOptions) -> Self {
         Self {
             path: util::base().join("server/go"),
+            name: option_label("go-wisp", &options),
+            options,
         }
     }
 }
 
+#[async_trait]
 impl WispServer for WispGo {
     fn name(&self) -> &str {
-        "go-wisp"
-    }
-
-    fn install(&self) -> Result<()> {
-        if !self.path.exists() {
-            Command::new("git")
-                .args(&["clone", "https://github.com/TheFalloutOf76/go-wisp"])
-                .arg(&self.path)
-                .status()
-                .context("Failed to clone go-wisp")?;
-        }
-        Command::new("go")
-            .args(&["get", "."])
-            .current_dir(&self.path)
-            .status()
-            .context("Failed to go get")?;
-        Command::new("go")
-            .args(&["build", "-ldflags", "-s -w", "-o", "go-wisp", "main.go"])
-            .current_dir(&self.path)
-            .status()
-            .context("Failed to build Go server")?;
-        Ok(())
+        &self.name
+    }
+
+    fn install(&self, log_file: &Path) -> Result<()> {
+        util::clone(GO_WISP_REPO, &self.path, log_file)?;
+        if util::mirror().is_none() {
+            util::run_logged(
+                Command::new("go")
+                    .args(["get", "."])
+                    .current_dir(&self.path),
+                log_file,
+                "go get",
+            )?;
+        }
+        util::run_logged(
+            util::go(&["build", "-ldflags", "-s -w", "-o", "go-wisp", "main.go"])
+                .current_dir(&self.path),
+            log_file,
+            "build Go server",
+        )
+    }
+
+    fn fetch(&self, mirror: &Path, log_file: &Path) -> Result<()> {
+        let repo = offline::fetch_repo(GO_WISP_REPO, mirror, log_file)?;
+        offline::vendor_go(&repo, log_file)
     }
 
     fn check_install(&self) -> bool {
         self.path.join("go-wisp").exists()
     }
 
-    fn run(&self, port: u16, log_file: &PathBuf) -> Result<Child> {
-        let config = serde_json::json!({
+    fn requirements(&self) -> Vec<&str> {
+        vec!["git", "go"]
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn version(&self) -> Option<String> {
+        util::git_version(&self.path)
+    }
+
+    fn update(&self, reference: Option<&str>, log_file: &Path) -> Result<()> {
+        util::git_update(&self.path, reference, log_file)?;
+        self.install(log_file)
+    }
+
+    fn clean(&self) -> Result<()> {
+        util::remove_dir(&self.path)
+    }
+
+    fn supports_deflate(&self) -> bool {
+        true
+    }
+
+    fn native_tls(&self) -> bool {
+        true
+    }
+
+    async fn prepare(&self, port: u16, options: &RunOptions) -> Result<()> {
+        let certificate = tls::certificate();
+        let mut config = serde_json::json!({
             "port": port.to_string(),
             "disableUDP": true,
             "tcpBufferSize": 131072,
@@ -221,21 +409,152 @@ impl WispServer for WispGo {
             "blacklist": { "hostnames": [] },
             "whitelist": { "hostnames": [] },
             "proxy": "",
-            "websocketPermessageDeflate": false,
-            "dnsServer": ""
+            "websocketPermessageDeflate": options.compression == Compression::On,
+            "dnsServer": "",
+            "tls": {
+                "enabled": options.transport == Transport::Wss,
+                "certFile": certificate.cert,
+                "keyFile": certificate.key
+            }
         });
+        for (key, value) in &self.options {
+            set_option(&mut config, key, value.clone());
+        }
         let config_path = self.path.join("config.json");
-        std::fs::write(&config_path, serde_json::to_string_pretty(&config)?)?;
-        util::run("./go-wisp", &[], Some(&self.path), log_file)
+        tokio::fs::write(&config_path, serde_json::to_string_pretty(&config)?).await?;
+        Ok(())
+    }
+
+    async fn start(
+        &self,
+        port: u16,
+        options: &RunOptions,
+        log_file: &Path,
+    ) -> Result<Box<dyn RunHandle>> {
+        let child = util::run(Role::Server, "./go-wisp", &[], Some(&self.path), log_file)?;
+        let tls = options.transport == Transport::Wss;
+        Ok(Box::new(ProcessHandle::server(child, port, tls)))
     }
 }
 
-pub fn get_implementations() -> Vec<Box<dyn WispServer>> {
-    vec![
-        Box::new(WispJS::new()),
-        Box::new(WispPy::new("python3")),
-        Box::new(EpoxyServer::new("singlethread")),
-        Box::new(EpoxyServer::new("multithread")),
-        Box::new(WispGo::new()),
-    ]
+pub type OptionSets = HashMap<String, Vec<ServerOptions>>;
+
+pub fn get_implementations(option_sets: &OptionSets) -> Vec<Box<dyn WispServer>> {
+    let sets = |kind: &str| {
+        option_sets
+            .get(kind)
+            .cloned()
+            .unwrap_or_else(|| vec![ServerOptions::new()])
+    };
+
+    let mut servers: Vec<Box<dyn WispServer>> = Vec::new();
+    for options in sets("wisp-js") {
+        servers.push(Box::new(WispJS::new(options)));
+    }
+    for options in sets("wisp-server-python") {
+        servers.push(Box::new(WispPy::new("python3", options)));
+    }
+    for threading in ["singlethread", "multithread"] {
+        for options in sets("epoxy-server") {
+            servers.push(Box::new(EpoxyServer::new(threading, options)));
+        }
+    }
+    for options in sets("go-wisp") {
+        servers.push(Box::new(WispGo::new(options)));
+    }
+    servers
+}
+
+pub fn parse_option(s: &str) -> Result<(String, String, String), String> {
+    let (target, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected <implementation>.<key>=<value>, got {}", s))?;
+    let (kind, key) = target
+        .split_once('.')
+        .ok_or_else(|| format!("Expected <implementation>.<key>=<value>, got {}", s))?;
+    Ok((kind.to_string(), key.to_string(), value.to_string()))
+}
+
+pub fn parse_value(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+// a JSON array sweeps over its elements, so values can be lists or objects themselves
+fn sweep_values(values: &str) -> Vec<Value> {
+    match serde_json::from_str(values) {
+        Ok(Value::Array(values)) => values,
+        _ => values.split(',').map(parse_value).collect(),
+    }
+}
+
+pub fn option_sets(
+    defaults: &HashMap<String, ServerOptions>,
+    fixed: &[(String, String, String)],
+    sweep: &[(String, String, String)],
+) -> Result<OptionSets> {
+    const KINDS: [&str; 4] = ["wisp-js", "wisp-server-python", "epoxy-server", "go-wisp"];
+    for (kind, _, _) in fixed.iter().chain(sweep) {
+        if !KINDS.contains(&kind.as_str()) {
+            return Err(anyhow!(
+                "Unknown server {}, expected one of {}",
+                kind,
+                KINDS.join(", ")
+            ));
+        }
+    }
+
+    let mut sets = OptionSets::new();
+    for kind in KINDS {
+        let mut base = defaults.get(kind).cloned().unwrap_or_default();
+        for (_, key, value) in fixed.iter().filter(|(k, _, _)| k == kind) {
+            base.insert(key.clone(), parse_value(value));
+        }
+
+        let mut expanded = vec![base];
+        for (_, key, values) in sweep.iter().filter(|(k, _, _)| k == kind) {
+            let mut next = Vec::new();
+            for options in &expanded {
+                for value in sweep_values(values) {
+                    let mut options = options.clone();
+                    options.insert(key.clone(), value);
+                    next.push(options);
+                }
+            }
+            expanded = next;
+        }
+
+        sets.insert(kind.to_string(), expanded);
+    }
+
+    Ok(sets)
+}
+
+fn option_label(name: &str, options: &ServerOptions) -> String {
+    if options.is_empty() {
+        return name.to_string();
+    }
+    let settings: Vec<String> = options
+        .iter()
+        .map(|(key, value)| match value {
+            Value::String(s) => format!("{}={}", key, s),
+            other => format!("{}={}", key, other),
+        })
+        .collect();
+    format!("{} [{}]", name, settings.join(", "))
+}
+
+fn set_option(config: &mut Value, path: &str, value: Value) {
+    let mut current = config;
+    let mut keys = path.split('.').peekable();
+    while let Some(key) = keys.next() {
+        if !current.is_object() {
+            *current = Value::Object(ServerOptions::new());
+        }
+        let map = current.as_object_mut().unwrap();
+        if keys.peek().is_none() {
+            map.insert(key.to_string(), value);
+            return;
+        }
+        current = map.entry(key).or_insert_with(|| Value::Object(ServerOptions::new()));
+    }
 }
@@ -0,0 +1,499 @@
+use crate::config::Config;
+use crate::placement::Placement;
+use crate::structure::{Bandwidth, RunOptions, Stats, TrafficPattern, Transport, WispServer};
+use crate::system::SystemInfo;
+use crate::{benchmark, client, echo, manage, placement, server, tls, util};
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// a resource only leaks when it climbs steadily, not when it jumps around
+const STEADY: f64 = 0.8;
+
+#[derive(Args, Debug)]
+pub struct SoakArgs {
+    #[arg(long, value_delimiter = ',')]
+    pub servers: Vec<String>,
+
+    #[arg(long, default_value = "wisp-js")]
+    pub client: String,
+
+    #[arg(long, default_value_t = 10)]
+    pub streams: usize,
+
+    #[arg(long, default_value_t = 1)]
+    pub instances: usize,
+
+    #[arg(long, value_parser = util::parse_duration, default_value = "1h")]
+    pub duration: Duration,
+
+    #[arg(long, value_parser = util::parse_duration, default_value = "60s")]
+    pub interval: Duration,
+
+    #[arg(long, value_parser = util::parse_duration_or_zero, default_value = "5m")]
+    pub warmup: Duration,
+
+    #[arg(long, default_value_t = 0.1)]
+    pub growth: f64,
+
+    #[arg(long, default_value_t = 0.1)]
+    pub decay: f64,
+
+    #[arg(long, value_enum)]
+    pub pattern: Option<TrafficPattern>,
+
+    #[arg(long, value_enum)]
+    pub transport: Option<Transport>,
+
+    #[arg(long, value_parser = util::parse_size)]
+    pub payload_size: Option<usize>,
+
+    #[arg(long, default_value = "wispmark-soak.md")]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub elapsed: u64,
+    pub bandwidth: Bandwidth,
+    pub stats: Stats,
+}
+
+/// Least squares fit of a value over the elapsed time.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Trend {
+    pub start: f64,
+    pub end: f64,
+    pub per_hour: f64,
+    pub r2: f64,
+}
+
+impl Trend {
+    pub fn fit(points: &[(f64, f64)]) -> Option<Self> {
+        if points.len() < 3 {
+            return None;
+        }
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let sxx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+        let sxy: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+        let syy: f64 = points.iter().map(|p| (p.1 - mean_y).powi(2)).sum();
+        if sxx == 0.0 {
+            return None;
+        }
+        let slope = sxy / sxx;
+        let at = |x: f64| mean_y + slope * (x - mean_x);
+        Some(Self {
+            start: at(points[0].0),
+            end: at(points[points.len() - 1].0),
+            per_hour: slope * 3600.0,
+            r2: if syy == 0.0 {
+                0.0
+            } else {
+                sxy * sxy / (sxx * syy)
+            },
+        })
+    }
+
+    pub fn change(&self) -> f64 {
+        if self.start <= 0.0 {
+            return 0.0;
+        }
+        (self.end - self.start) / self.start
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Soak {
+    pub server: String,
+    pub samples: Vec<Sample>,
+    pub failed: Option<String>,
+    pub throughput: Option<Trend>,
+    pub rss: Option<Trend>,
+    pub open_fds: Option<Trend>,
+    pub warnings: Vec<String>,
+}
+
+impl Soak {
+    fn analyze(&mut self, warmup: u64, growth: f64, decay: f64) {
+        let settled: Vec<&Sample> = self
+            .samples
+            .iter()
+            .filter(|s| s.elapsed >= warmup)
+            .collect();
+        let fit = |value: fn(&Sample) -> Option<f64>| {
+            let points: Vec<(f64, f64)> = settled
+                .iter()
+                .filter_map(|s| Some((s.elapsed as f64, value(s)?)))
+                .collect();
+            Trend::fit(&points)
+        };
+        self.throughput = fit(|s| Some(s.bandwidth.up + s.bandwidth.down));
+        self.rss = fit(|s| s.stats.rss.map(|rss| rss as f64 / 1024.0 / 1024.0));
+        self.open_fds = fit(|s| s.stats.open_fds.map(|fds| fds as f64));
+
+        self.warnings.clear();
+        if let Some(reason) = &self.failed {
+            self.warnings.push(reason.clone());
+        }
+        if let Some(t) = self.rss.filter(|t| t.r2 >= STEADY && t.change() > growth) {
+            self.warnings.push(format!(
+                "RSS grew steadily from {:.1} to {:.1} MiB ({:+.1} MiB/h)",
+                t.start, t.end, t.per_hour
+            ));
+        }
+        if let Some(t) = self
+            .open_fds
+            .filter(|t| t.r2 >= STEADY && t.change() > growth)
+        {
+            self.warnings.push(format!(
+                "Open file descriptors grew steadily from {:.0} to {:.0} ({:+.1}/h)",
+                t.start, t.end, t.per_hour
+            ));
+        }
+        if let Some(t) = self
+            .throughput
+            .filter(|t| t.r2 >= STEADY && t.change() < -decay)
+        {
+            self.warnings.push(format!(
+                "Throughput degraded steadily from {:.2} to {:.2} MiB/s ({:.0}%)",
+                t.start,
+                t.end,
+                t.change() * 100.0
+            ));
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SoakResults {
+    pub system: SystemInfo,
+    pub client: String,
+    pub options: RunOptions,
+    pub duration: u64,
+    pub interval: u64,
+    pub warmup: u64,
+    #[serde(default, skip_serializing_if = "Placement::is_empty")]
+    pub placement: Placement,
+    pub partial: bool,
+    pub soaks: Vec<Soak>,
+}
+
+pub async fn run(args: SoakArgs, config: &Config) -> Result<()> {
+    let settings = config
+        .run
+        .settings(server::option_sets(&config.server_options, &[], &[])?)?;
+    let options = RunOptions {
+        payload_size: args.payload_size.unwrap_or(settings.payload_sizes[0]),
+        pattern: args.pattern.unwrap_or(settings.patterns[0]),
+        transport: args.transport.unwrap_or(settings.transports[0]),
+        compression: settings.compressions[0],
+        payload_mode: settings.payload_modes[0],
+    };
+    let interval = args.interval.as_secs();
+
+    let servers: Vec<Box<dyn WispServer>> = server::get_implementations(&settings.server_options)
+        .into_iter()
+        .filter(|s| util::name_matches(s.name(), &args.servers))
+        .collect();
+    let Some(client) = client::get_matrix(&[args.streams], &[args.instances])
+        .into_iter()
+        .find(|c| util::name_matches(c.name(), std::slice::from_ref(&args.client)))
+    else {
+        bail!(
+            "Unknown client {}, expected wisp-js or wisp-mux",
+            args.client
+        );
+    };
+    if servers.is_empty() {
+        bail!("No server matches the selected implementations");
+    }
+    if !client.supports(&options) {
+        bail!("{} doesn't support {}", args.client, options.section());
+    }
+
+    util::sudo()?;
+    let _placement = placement::activate(&settings.placement)?;
+    if !settings.placement.is_empty() {
+        println!("Placement: {}", settings.placement);
+    }
+    echo::get_echo().await?;
+    if options.transport == Transport::Wss {
+        tls::generate_certificate()?;
+    }
+    let clients = vec![client];
+    let mut system = SystemInfo::collect();
+    let failed = manage::install_missing(&servers, &clients, settings.jobs);
+    if !failed.is_empty() {
+        let failed: Vec<String> = failed.into_iter().collect();
+        bail!("Failed to install {}", failed.join(", "));
+    }
+    system.record_versions(&servers, &clients);
+    let client = clients.into_iter().next().unwrap();
+    let log_dir = util::log_dir();
+    tokio::fs::create_dir_all(&log_dir).await?;
+
+    let mut results = SoakResults {
+        system,
+        client: client.name().to_string(),
+        options,
+        duration: args.duration.as_secs(),
+        interval,
+        warmup: args.warmup.as_secs(),
+        placement: settings.placement.clone(),
+        partial: true,
+        soaks: Vec::new(),
+    };
+    let target = echo::run_target(options.pattern, settings.fairness)?;
+    util::wait_for_tcp(util::ECHO_PORT, util::SERVER_TIMEOUT).await?;
+
+    let measure = async {
+        for server in &servers {
+            results.soaks.push(Soak {
+                server: server.name().to_string(),
+                samples: Vec::new(),
+                failed: None,
+                throughput: None,
+                rss: None,
+                open_fds: None,
+                warnings: Vec::new(),
+            });
+            let soak = results.soaks.last_mut().unwrap();
+
+            let name = util::log_name(server.name());
+            let pair = benchmark::start_pair(
+                server.as_ref(),
+                client.as_ref(),
+                &settings,
+                &options,
+                &log_dir.join(format!("SOAK_SERVER_{}.log", name)),
+                &log_dir.join(format!("SOAK_CLIENT_{}.log", name)),
+            )
+            .await;
+            let mut pair = match pair {
+                Ok(pair) => pair,
+                Err(reason) => {
+                    soak.failed = Some(reason.to_string());
+                    continue;
+                }
+            };
+
+            println!(
+                "Soaking {} for {}, sampling every {}s...",
+                soak.server,
+                util::format_duration(args.duration.as_secs()),
+                interval
+            );
+            let started = Instant::now();
+            while started.elapsed() < args.duration {
+                let bandwidth = match util::get_bandwidth(util::ECHO_PORT, interval).await {
+                    Ok(bandwidth) => bandwidth,
+                    Err(e) => {
+                        soak.failed = Some(format!(
+                            "Stopped after {}: {}",
+                            util::format_duration(started.elapsed().as_secs()),
+                            e
+                        ));
+                        break;
+                    }
+                };
+                let sample = Sample {
+                    elapsed: started.elapsed().as_secs(),
+                    bandwidth,
+                    stats: pair.server.stats().await,
+                };
+                println!(
+                    "[{}] {}, RSS {}, {} open fds",
+                    util::format_duration(sample.elapsed),
+                    sample.bandwidth,
+                    sample.stats.rss.map_or("?".to_string(), util::format_mib),
+                    sample
+                        .stats
+                        .open_fds
+                        .map_or("?".to_string(), |fds| fds.to_string())
+                );
+                soak.samples.push(sample);
+            }
+            pair.stop().await;
+        }
+    };
+    let completed = tokio::select! {
+        _ = measure => true,
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!("\nInterrupted, writing the samples taken so far...");
+            if let Err(e) = util::kill(util::WISP_PORT) {
+                eprintln!("Warning: Failed to stop the server: {}", e);
+            }
+            false
+        }
+    };
+    drop(target);
+    results.partial = !completed;
+    for soak in &mut results.soaks {
+        soak.analyze(results.warmup, args.growth, args.decay);
+    }
+
+    let markdown = markdown(&results);
+    println!("{}", markdown);
+    tokio::fs::write(&args.output, &markdown).await?;
+    println!("\nMarkdown results written to: {}", args.output.display());
+    let json_output = args.output.with_extension("json");
+    let json = serde_json::to_string_pretty(&results).context("Failed to serialize results")?;
+    tokio::fs::write(&json_output, json)
+        .await
+        .with_context(|| format!("Failed to write {}", json_output.display()))?;
+    println!("JSON results written to: {}", json_output.display());
+    Ok(())
+}
+
+pub fn markdown(results: &SoakResults) -> String {
+    let mut output = String::new();
+    if results.partial {
+        output.push_str("**Partial results: the soak was interrupted before it finished.**\n\n");
+    }
+    output.push_str(&results.system.markdown());
+    output.push_str(&format!(
+        "Client: {}, {}, {} payloads\n",
+        results.client,
+        results.options.section(),
+        util::format_size(results.options.payload_size),
+    ));
+    if !results.placement.is_empty() {
+        output.push_str(&format!("Placement: {}\n", results.placement));
+    }
+    output.push_str(&format!(
+        "Soaked for {}, sampled every {}s. Trends ignore the first {}.\n",
+        util::format_duration(results.duration),
+        results.interval,
+        util::format_duration(results.warmup)
+    ));
+
+    let trend = |t: Option<Trend>, precision: usize, unit: &str| {
+        t.map_or_else(
+            || "-".to_string(),
+            |t| {
+                format!(
+                    "{:.*} → {:.*}{}",
+                    precision, t.start, precision, t.end, unit
+                )
+            },
+        )
+    };
+    let mut summary = vec![vec![
+        "Server".to_string(),
+        "Samples".to_string(),
+        "Throughput".to_string(),
+        "RSS".to_string(),
+        "Open fds".to_string(),
+        "Warnings".to_string(),
+    ]];
+    for soak in &results.soaks {
+        summary.push(vec![
+            soak.server.clone(),
+            soak.samples.len().to_string(),
+            trend(soak.throughput, 2, " MiB/s"),
+            trend(soak.rss, 1, " MiB"),
+            trend(soak.open_fds, 0, ""),
+            soak.warnings.len().to_string(),
+        ]);
+    }
+    output.push_str("\n## Summary\n\n");
+    output.push_str(&benchmark::format_table(&summary));
+
+    for soak in &results.soaks {
+        output.push_str(&format!("\n## {}\n\n", soak.server));
+        for warning in &soak.warnings {
+            output.push_str(&format!("- **Warning:** {}\n", warning));
+        }
+        if !soak.warnings.is_empty() {
+            output.push('\n');
+        }
+        let mut table = vec![vec![
+            "Elapsed".to_string(),
+            "Throughput".to_string(),
+            "RSS".to_string(),
+            "Open fds".to_string(),
+        ]];
+        for sample in &soak.samples {
+            table.push(vec![
+                util::format_duration(sample.elapsed),
+                sample.bandwidth.to_string(),
+                sample.stats.rss.map_or("-".to_string(), util::format_mib),
+                sample
+                    .stats
+                    .open_fds
+                    .map_or("-".to_string(), |fds| fds.to_string()),
+            ]);
+        }
+        output.push_str(&benchmark::format_table(&table));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(values: &[f64]) -> Vec<(f64, f64)> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| (i as f64 * 60.0, v))
+            .collect()
+    }
+
+    #[test]
+    fn too_few_points() {
+        assert!(Trend::fit(&series(&[1.0, 2.0])).is_none());
+        assert!(Trend::fit(&[(5.0, 1.0), (5.0, 2.0), (5.0, 3.0)]).is_none());
+    }
+
+    #[test]
+    fn flat() {
+        let t = Trend::fit(&series(&[100.0; 10])).unwrap();
+        assert_eq!(t.start, 100.0);
+        assert_eq!(t.end, 100.0);
+        assert_eq!(t.per_hour, 0.0);
+        assert_eq!(t.r2, 0.0);
+        assert_eq!(t.change(), 0.0);
+    }
+
+    #[test]
+    fn rising() {
+        // one unit per minute, from 100 to 109
+        let values: Vec<f64> = (0..10).map(|i| 100.0 + i as f64).collect();
+        let t = Trend::fit(&series(&values)).unwrap();
+        assert!((t.start - 100.0).abs() < 1e-9);
+        assert!((t.end - 109.0).abs() < 1e-9);
+        assert!((t.per_hour - 60.0).abs() < 1e-9);
+        assert!((t.r2 - 1.0).abs() < 1e-9);
+        assert!((t.change() - 0.09).abs() < 1e-9);
+    }
+
+    #[test]
+    fn falling() {
+        let values: Vec<f64> = (0..10).map(|i| 100.0 - 2.0 * i as f64).collect();
+        let t = Trend::fit(&series(&values)).unwrap();
+        assert!((t.change() + 0.18).abs() < 1e-9);
+        assert!(t.r2 > STEADY);
+    }
+
+    #[test]
+    fn noisy() {
+        // alternating around a flat line, the fit shouldn't be taken for a trend
+        let values = [
+            100.0, 80.0, 120.0, 90.0, 110.0, 70.0, 130.0, 95.0, 105.0, 100.0,
+        ];
+        let t = Trend::fit(&series(&values)).unwrap();
+        assert!(t.r2 < STEADY);
+    }
+
+    #[test]
+    fn change_from_zero() {
+        let t = Trend::fit(&series(&[0.0, 1.0, 2.0, 3.0])).unwrap();
+        assert_eq!(t.change(), 0.0);
+    }
+}
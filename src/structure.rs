@@ -1,13 +1,190 @@
+use crate::impair::Impairment;
+use crate::noise;
+use crate::placement::Placement;
+use crate::server::OptionSets;
+use crate::util;
 use anyhow::Result;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::process::Child;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrafficPattern {
+    Echo,
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Ws,
+    Wss,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Off,
+    On,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadMode {
+    Compressible,
+    Incompressible,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Markdown,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RunOptions {
+    pub payload_size: usize,
+    pub pattern: TrafficPattern,
+    pub transport: Transport,
+    pub compression: Compression,
+    pub payload_mode: PayloadMode,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BenchmarkSettings {
+    pub duration: u64,
+    pub jobs: usize,
+    #[serde(default)]
+    pub repeats: usize,
+    #[serde(default)]
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub clients: Vec<String>,
+    #[serde(default)]
+    pub streams: Vec<usize>,
+    #[serde(default)]
+    pub instances: Vec<usize>,
+    pub payload_sizes: Vec<usize>,
+    pub patterns: Vec<TrafficPattern>,
+    pub transports: Vec<Transport>,
+    pub compressions: Vec<Compression>,
+    pub payload_modes: Vec<PayloadMode>,
+    pub server_options: OptionSets,
+    pub impairment: Option<Impairment>,
+    #[serde(default)]
+    pub fairness: bool,
+    #[serde(default, skip_serializing_if = "Placement::is_empty")]
+    pub placement: Placement,
+    #[serde(default = "default_baseline")]
+    pub baseline: bool,
+    /// Overrides the pipelining depth of every baseline shape.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseline_depth: Option<usize>,
+    /// Share of the CPU time other processes may use before a measurement counts as noisy.
+    #[serde(default = "default_max_noise")]
+    pub max_noise: f64,
+    #[serde(default)]
+    pub abort_when_busy: bool,
+}
+
+fn default_max_noise() -> f64 {
+    noise::DEFAULT_MAX_NOISE
+}
+
+fn default_baseline() -> bool {
+    true
+}
+
+/// The raw TCP traffic that stands in for a client in the baseline measurement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LoadShape {
+    /// Connections to the target, one for every Wisp stream.
+    pub connections: usize,
+    pub payload_size: usize,
+    /// Payloads each connection writes ahead of the echoes it has read back.
+    pub depth: usize,
+    pub pattern: TrafficPattern,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Bandwidth {
+    pub up: f64,
+    pub down: f64,
+}
+
+/// Resource usage of a running implementation, summed over its processes.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Stats {
+    pub rss: Option<u64>,
+    pub open_fds: Option<usize>,
+    pub cpu_time: Option<f64>,
+}
+
+impl Stats {
+    pub fn add(&mut self, other: &Stats) {
+        fn sum<T: std::ops::Add<Output = T>>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(b),
+            }
+        }
+        self.rss = sum(self.rss, other.rss);
+        self.open_fds = sum(self.open_fds, other.open_fds);
+        self.cpu_time = sum(self.cpu_time, other.cpu_time);
+    }
+}
+
+/// A started server or set of clients.
+#[async_trait]
+pub trait RunHandle: Send {
+    /// Resolves once the implementation accepts connections.
+    async fn ready(&mut self) -> Result<()>;
+    async fn stats(&mut self) -> Stats {
+        Stats::default()
+    }
+    async fn shutdown(self: Box<Self>) -> Result<()>;
+}
+
+#[async_trait]
 pub trait WispClient: Send + Sync {
     fn name(&self) -> &str;
-    fn install(&self) -> Result<()>;
+    fn install(&self, log_file: &Path) -> Result<()>;
+    fn fetch(&self, mirror: &Path, log_file: &Path) -> Result<()>;
     fn check_install(&self) -> bool;
-    fn run(&self, server_port: u16, target_port: u16, log_file: &PathBuf) -> Result<Vec<Child>>;
+    fn requirements(&self) -> Vec<&str>;
+    fn path(&self) -> &Path;
+    fn version(&self) -> Option<String>;
+    fn update(&self, reference: Option<&str>, log_file: &Path) -> Result<()>;
+    fn clean(&self) -> Result<()>;
+    fn supports(&self, _options: &RunOptions) -> bool {
+        true
+    }
+    /// The traffic the baseline sends in place of this client, 10 connections writing one
+    /// payload at a time unless the client says otherwise.
+    fn shape(&self, options: &RunOptions) -> LoadShape {
+        LoadShape {
+            connections: 10,
+            payload_size: options.payload_size,
+            depth: 1,
+            pattern: options.pattern,
+        }
+    }
+    async fn prepare(&self, _options: &RunOptions) -> Result<()> {
+        Ok(())
+    }
+    async fn start(
+        &self,
+        server_port: u16,
+        target_port: u16,
+        options: &RunOptions,
+        log_file: &Path,
+    ) -> Result<Box<dyn RunHandle>>;
 }
 
 pub struct WispNode {
@@ -25,15 +202,41 @@ pub struct EpoxyClient {
     pub name: String,
 }
 
+#[async_trait]
 pub trait WispServer: Send + Sync {
     fn name(&self) -> &str;
-    fn install(&self) -> Result<()>;
+    fn install(&self, log_file: &Path) -> Result<()>;
+    fn fetch(&self, mirror: &Path, log_file: &Path) -> Result<()>;
     fn check_install(&self) -> bool;
-    fn run(&self, port: u16, log_file: &PathBuf) -> Result<Child>;
+    fn requirements(&self) -> Vec<&str>;
+    fn path(&self) -> &Path;
+    fn version(&self) -> Option<String>;
+    fn update(&self, reference: Option<&str>, log_file: &Path) -> Result<()>;
+    fn clean(&self) -> Result<()>;
+    fn native_tls(&self) -> bool {
+        false
+    }
+    fn supports_deflate(&self) -> bool {
+        false
+    }
+    /// Called before every start, e.g. to write the config for this run.
+    async fn prepare(&self, _port: u16, _options: &RunOptions) -> Result<()> {
+        Ok(())
+    }
+    async fn start(
+        &self,
+        port: u16,
+        options: &RunOptions,
+        log_file: &Path,
+    ) -> Result<Box<dyn RunHandle>>;
 }
 
+pub type ServerOptions = serde_json::Map<String, serde_json::Value>;
+
 pub struct WispJS {
     pub path: PathBuf,
+    pub name: String,
+    pub options: ServerOptions,
 }
 
 pub struct WispPy {
@@ -42,6 +245,7 @@ pub struct WispPy {
     pub venv: PathBuf,
     pub python: String,
     pub name: String,
+    pub options: ServerOptions,
 }
 
 pub struct EpoxyServer {
@@ -49,29 +253,139 @@ pub struct EpoxyServer {
     pub epoxy_src: PathBuf,
     pub threading: String,
     pub name: String,
+    pub options: ServerOptions,
 }
 
 pub struct WispGo {
     pub path: PathBuf,
+    pub name: String,
+    pub options: ServerOptions,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum BenchmarkResult {
-    Success(f64),
+    Success(Bandwidth),
     Failed(String),
 }
 
+/// How the repeats of a pair went, the spread is between the fastest and slowest successful run.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Repeats {
+    pub succeeded: usize,
+    pub total: usize,
+    pub spread: Bandwidth,
+}
+
+impl Repeats {
+    pub fn of(runs: &[BenchmarkResult]) -> Option<Self> {
+        if runs.len() < 2 {
+            return None;
+        }
+        let bandwidths: Vec<Bandwidth> = runs
+            .iter()
+            .filter_map(|r| match r {
+                BenchmarkResult::Success(bw) => Some(*bw),
+                BenchmarkResult::Failed(_) => None,
+            })
+            .collect();
+        let spread = |f: fn(&Bandwidth) -> f64| {
+            let values = bandwidths.iter().map(f);
+            let max = values.clone().fold(f64::MIN, f64::max);
+            let min = values.fold(f64::MAX, f64::min);
+            (max - min).max(0.0)
+        };
+        Some(Self {
+            succeeded: bandwidths.len(),
+            total: runs.len(),
+            spread: Bandwidth {
+                up: spread(|bw| bw.up),
+                down: spread(|bw| bw.down),
+            },
+        })
+    }
+}
+
+#[derive(Default)]
 pub struct BenchmarkResults {
-    pub results: HashMap<String, HashMap<String, BenchmarkResult>>,
+    pub results: HashMap<String, HashMap<String, HashMap<RunOptions, BenchmarkResult>>>,
     pub server_order: Vec<String>,
     pub client_order: Vec<String>,
+    pub option_order: Vec<RunOptions>,
+    /// Servers whose `wss` results went through the built-in TLS proxy.
+    pub tls_proxied: Vec<String>,
+}
+
+impl fmt::Display for TrafficPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrafficPattern::Echo => write!(f, "echo"),
+            TrafficPattern::Upload => write!(f, "upload"),
+            TrafficPattern::Download => write!(f, "download"),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Ws => write!(f, "ws"),
+            Transport::Wss => write!(f, "wss"),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Off => write!(f, "off"),
+            Compression::On => write!(f, "on"),
+        }
+    }
 }
 
-impl BenchmarkResult {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for PayloadMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            BenchmarkResult::Success(speed) => format!("{:.2} MiB/s", speed),
-            BenchmarkResult::Failed(reason) => reason.clone(),
+            PayloadMode::Compressible => write!(f, "compressible"),
+            PayloadMode::Incompressible => write!(f, "incompressible"),
+        }
+    }
+}
+
+impl RunOptions {
+    pub fn section(&self) -> String {
+        format!(
+            "{} traffic over {}, deflate {}, {} payloads",
+            self.pattern, self.transport, self.compression, self.payload_mode
+        )
+    }
+}
+
+impl fmt::Display for LoadShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} connection(s), {} {} payloads, {} in flight",
+            self.connections,
+            util::format_size(self.payload_size),
+            self.pattern,
+            self.depth
+        )
+    }
+}
+
+impl fmt::Display for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2} / {:.2} MiB/s", self.up, self.down)
+    }
+}
+
+impl fmt::Display for BenchmarkResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BenchmarkResult::Success(bandwidth) => write!(f, "{}", bandwidth),
+            BenchmarkResult::Failed(reason) => write!(f, "{}", reason),
         }
     }
 }
@@ -82,24 +396,42 @@ impl BenchmarkResults {
             results: HashMap::new(),
             server_order: Vec::new(),
             client_order: Vec::new(),
+            option_order: Vec::new(),
+            tls_proxied: Vec::new(),
         }
     }
 
-    pub fn add(&mut self, server: String, client: String, result: BenchmarkResult) {
+    pub fn add(
+        &mut self,
+        server: String,
+        client: String,
+        options: RunOptions,
+        result: BenchmarkResult,
+    ) {
         if !self.server_order.contains(&server) {
             self.server_order.push(server.clone());
         }
         if !self.client_order.contains(&client) {
             self.client_order.push(client.clone());
         }
+        if !self.option_order.contains(&options) {
+            self.option_order.push(options);
+        }
 
         self.results
             .entry(server)
-            .or_insert_with(HashMap::new)
-            .insert(client, result);
+            .or_default()
+            .entry(client)
+            .or_default()
+            .insert(options, result);
     }
 
-    pub fn get(&self, server: &str, client: &str) -> Option<&BenchmarkResult> {
-        self.results.get(server)?.get(client)
+    pub fn get(
+        &self,
+        server: &str,
+        client: &str,
+        options: &RunOptions,
+    ) -> Option<&BenchmarkResult> {
+        self.results.get(server)?.get(client)?.get(options)
     }
 }
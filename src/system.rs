@@ -0,0 +1,297 @@
+use crate::structure::{WispClient, WispServer};
+use crate::util;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::{Command, Stdio};
+
+const TOOLCHAINS: [&str; 4] = ["node", "python3", "go", "rustc"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NumaNode {
+    pub node: usize,
+    pub cpus: String,
+}
+
+/// The machine a run was measured on.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SystemInfo {
+    pub cpu: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cores: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smt: Option<bool>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub governors: Vec<String>,
+    /// Average current frequency in MHz.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<u64>,
+    /// Highest maximum frequency in MHz.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_frequency: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub numa: Vec<NumaNode>,
+    /// Total memory in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    /// 1, 5 and 15 minute load averages before the run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load: Option<[f64; 3]>,
+    #[serde(default)]
+    pub toolchains: BTreeMap<String, String>,
+    #[serde(default)]
+    pub servers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub clients: BTreeMap<String, String>,
+}
+
+impl SystemInfo {
+    pub fn collect() -> Self {
+        let (frequency, max_frequency) = frequencies();
+        Self {
+            cpu: util::get_cpu_info().unwrap_or_else(|_| "Unknown CPU".to_string()),
+            cores: cores(),
+            smt: read("/sys/devices/system/cpu/smt/active").map(|active| active == "1"),
+            governors: governors(),
+            frequency,
+            max_frequency,
+            numa: numa(),
+            memory: memory(),
+            kernel: read("/proc/sys/kernel/osrelease"),
+            load: load(),
+            toolchains: TOOLCHAINS
+                .iter()
+                .filter_map(|tool| Some((tool.to_string(), short_version(&tool_version(tool)?))))
+                .collect(),
+            servers: BTreeMap::new(),
+            clients: BTreeMap::new(),
+        }
+    }
+
+    // keyed by name, so variants of one implementation share an entry
+    pub fn record_versions(
+        &mut self,
+        servers: &[Box<dyn WispServer>],
+        clients: &[Box<dyn WispClient>],
+    ) {
+        let base = |name: &str| {
+            let name = name.split(" [").next().unwrap_or(name);
+            name.split(" (").next().unwrap_or(name).to_string()
+        };
+        for server in servers {
+            if let Some(version) = server.version() {
+                self.servers.insert(base(server.name()), version);
+            }
+        }
+        for client in clients {
+            if let Some(version) = client.version() {
+                self.clients.insert(base(client.name()), version);
+            }
+        }
+    }
+
+    pub fn markdown(&self) -> String {
+        let mut cpu = vec![self.cpu.clone()];
+        if let Some(cores) = self.cores {
+            cpu.push(format!(
+                "{} core{}",
+                cores,
+                if cores == 1 { "" } else { "s" }
+            ));
+        }
+        if let Some(smt) = self.smt {
+            cpu.push(format!("SMT {}", if smt { "on" } else { "off" }));
+        }
+        if !self.governors.is_empty() {
+            cpu.push(format!("{} governor", self.governors.join("/")));
+        }
+        match (self.frequency, self.max_frequency) {
+            (Some(current), Some(max)) => cpu.push(format!("{} MHz (max {} MHz)", current, max)),
+            (Some(mhz), None) | (None, Some(mhz)) => cpu.push(format!("{} MHz", mhz)),
+            (None, None) => {}
+        }
+
+        let mut output = format!("CPU: {}\n", cpu.join(", "));
+        if self.numa.len() > 1 {
+            let nodes: Vec<String> = self
+                .numa
+                .iter()
+                .map(|n| format!("node {}: {}", n.node, n.cpus))
+                .collect();
+            output.push_str(&format!("NUMA: {}\n", nodes.join(", ")));
+        }
+        if let Some(memory) = self.memory {
+            output.push_str(&format!(
+                "Memory: {:.1} GiB\n",
+                memory as f64 / (1u64 << 30) as f64
+            ));
+        }
+        if let Some(kernel) = &self.kernel {
+            output.push_str(&format!("Kernel: {}\n", kernel));
+        }
+        if let Some([one, five, fifteen]) = self.load {
+            output.push_str(&format!(
+                "Load before the run: {:.2} / {:.2} / {:.2}\n",
+                one, five, fifteen
+            ));
+        }
+        let list = |versions: &BTreeMap<String, String>| {
+            versions
+                .iter()
+                .map(|(name, version)| format!("{} {}", name, version))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        if !self.toolchains.is_empty() {
+            output.push_str(&format!("Toolchains: {}\n", list(&self.toolchains)));
+        }
+        if !self.servers.is_empty() {
+            output.push_str(&format!("Servers: {}\n", list(&self.servers)));
+        }
+        if !self.clients.is_empty() {
+            output.push_str(&format!("Clients: {}\n", list(&self.clients)));
+        }
+        output.push('\n');
+        output
+    }
+}
+
+fn read(path: &str) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+pub fn tool_version(tool: &str) -> Option<String> {
+    let args: &[&str] = match tool {
+        "go" => &["version"],
+        // iftop has no version flag and needs root to do anything useful
+        "iftop" => return None,
+        _ => &["--version"],
+    };
+    let output = Command::new(tool)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    let text = if output.stdout.is_empty() {
+        output.stderr
+    } else {
+        output.stdout
+    };
+    String::from_utf8_lossy(&text)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+}
+
+// the first word that looks like a version, e.g. 1.22.4 from "go version go1.22.4 linux/amd64"
+fn short_version(version: &str) -> String {
+    version
+        .split_whitespace()
+        .map(|word| word.trim_start_matches("go").trim_start_matches('v'))
+        .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))
+        .unwrap_or(version)
+        .to_string()
+}
+
+fn cpu_dirs() -> Vec<std::path::PathBuf> {
+    let Ok(entries) = std::fs::read_dir("/sys/devices/system/cpu") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            name.strip_prefix("cpu")
+                .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+        })
+        .map(|e| e.path())
+        .collect()
+}
+
+pub fn governors() -> Vec<String> {
+    let governors: BTreeSet<String> = cpu_dirs()
+        .iter()
+        .filter_map(|dir| std::fs::read_to_string(dir.join("cpufreq/scaling_governor")).ok())
+        .map(|governor| governor.trim().to_string())
+        .collect();
+    governors.into_iter().collect()
+}
+
+fn frequencies() -> (Option<u64>, Option<u64>) {
+    let khz = |file: &str| -> Vec<u64> {
+        cpu_dirs()
+            .iter()
+            .filter_map(|dir| std::fs::read_to_string(dir.join("cpufreq").join(file)).ok())
+            .filter_map(|khz| khz.trim().parse().ok())
+            .collect()
+    };
+    let current = khz("scaling_cur_freq");
+    let average =
+        (!current.is_empty()).then(|| current.iter().sum::<u64>() / current.len() as u64 / 1000);
+    let max = khz("cpuinfo_max_freq")
+        .into_iter()
+        .max()
+        .map(|khz| khz / 1000);
+    (average, max)
+}
+
+// distinct (physical id, core id) pairs
+fn cores() -> Option<usize> {
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+    let mut physical = "0";
+    let mut cores = BTreeSet::new();
+    for line in cpuinfo.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        match key.trim() {
+            "physical id" => physical = value.trim(),
+            "core id" => {
+                cores.insert((physical, value.trim()));
+            }
+            _ => {}
+        }
+    }
+    (!cores.is_empty()).then_some(cores.len())
+}
+
+fn numa() -> Vec<NumaNode> {
+    let Ok(entries) = std::fs::read_dir("/sys/devices/system/node") else {
+        return Vec::new();
+    };
+    let mut nodes: Vec<NumaNode> = entries
+        .flatten()
+        .filter_map(|e| {
+            let node = e
+                .file_name()
+                .to_string_lossy()
+                .strip_prefix("node")?
+                .parse()
+                .ok()?;
+            let cpus = std::fs::read_to_string(e.path().join("cpulist")).ok()?;
+            Some(NumaNode {
+                node,
+                cpus: cpus.trim().to_string(),
+            })
+        })
+        .collect();
+    nodes.sort_by_key(|n| n.node);
+    nodes
+}
+
+fn memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemTotal:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+pub fn load() -> Option<[f64; 3]> {
+    let loadavg = read("/proc/loadavg")?;
+    let mut values = loadavg.split_whitespace().map(|v| v.parse().ok());
+    Some([values.next()??, values.next()??, values.next()??])
+}
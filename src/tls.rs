@@ -0,0 +1,86 @@
+use crate::util;
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+pub struct Certificate {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+pub fn certificate() -> Certificate {
+    let dir = util::base().join("tls");
+    Certificate {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+    }
+}
+
+pub fn generate_certificate() -> Result<Certificate> {
+    let paths = certificate();
+    if paths.cert.exists() && paths.key.exists() {
+        return Ok(paths);
+    }
+
+    println!("Generating self-signed certificate...");
+    let certified = rcgen::generate_simple_self_signed(vec![
+        "127.0.0.1".to_string(),
+        "localhost".to_string(),
+    ])
+    .context("Failed to generate certificate")?;
+
+    if let Some(parent) = paths.cert.parent() {
+        std::fs::create_dir_all(parent).context("Failed to create tls directory")?;
+    }
+    std::fs::write(&paths.cert, certified.cert.pem()).context("Failed to write certificate")?;
+    std::fs::write(&paths.key, certified.key_pair.serialize_pem())
+        .context("Failed to write private key")?;
+
+    Ok(paths)
+}
+
+fn acceptor(paths: &Certificate) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(&paths.cert)
+        .context("Failed to read certificate")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to parse certificate")?;
+    let key = PrivateKeyDer::from_pem_file(&paths.key).context("Failed to read private key")?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Failed to build TLS config")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub async fn spawn_proxy(listen_port: u16, server_port: u16) -> Result<JoinHandle<Result<()>>> {
+    let acceptor = acceptor(&certificate())?;
+    let listener = TcpListener::bind(("127.0.0.1", listen_port))
+        .await
+        .context("Failed to bind TLS proxy")?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let (client, _) = listener.accept().await?;
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let _ = client.set_nodelay(true);
+                let Ok(mut client) = acceptor.accept(client).await else {
+                    return;
+                };
+                let Ok(mut server) = TcpStream::connect(("127.0.0.1", server_port)).await else {
+                    return;
+                };
+                let _ = server.set_nodelay(true);
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+            });
+        }
+    }))
+}
@@ -1,19 +1,30 @@
-use anyhow::{anyhow, Context, Result};
-use dirs::config_dir;
+use crate::placement::{self, Role};
+use crate::structure::Bandwidth;
+use anyhow::{anyhow, bail, Context, Result};
 use once_cell::sync::Lazy;
 use regex::Regex;
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::sync::OnceLock;
+use std::sync::{Mutex, OnceLock};
 use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio::time::sleep;
 
 static BASE_DIR: OnceLock<PathBuf> = OnceLock::new();
+static MIRROR_DIR: OnceLock<PathBuf> = OnceLock::new();
+// parallel installs share checkouts, so git operations are serialised
+static GIT_LOCK: Mutex<()> = Mutex::new(());
 pub const WISP_PORT: u16 = 6001;
 pub const ECHO_PORT: u16 = 6002;
+pub const RELAY_PORT: u16 = 6003;
+pub const TLS_PORT: u16 = 6004;
+pub const STATS_PORT: u16 = 6005;
+pub const FAULT_PORT: u16 = 6006;
+// nothing listens here, for CONNECTs that should be refused
+pub const CLOSED_PORT: u16 = 6007;
 pub const SERVER_TIMEOUT: u64 = 5;
+pub const EPOXY_REPO: &str = "https://github.com/MercuryWorkshop/epoxy-tls";
 
 static P_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r":(\d+).+?(\d+)/").unwrap());
 
@@ -24,31 +35,7 @@ static CPU_NAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"model name.+?: (.
 
 static CPU_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"processor.+?: (.+?)\n").unwrap());
 
-#[derive(Serialize, Deserialize, Default)]
-struct Config {
-    base_dir: Option<PathBuf>,
-}
-
-fn config_path() -> PathBuf {
-    config_dir()
-        .unwrap_or_else(|| PathBuf::from("."))
-        .join("wispmark")
-        .join("config.json")
-}
-
-fn load_config() -> Result<Config> {
-    let path = config_path();
-
-    if !path.exists() {
-        return Ok(Config::default());
-    }
-
-    let contents = std::fs::read_to_string(&path).context("Failed to read config")?;
-
-    serde_json::from_str(&contents).context("Failed to parse config")
-}
-
-pub fn save_default_base_dir(dir: PathBuf) -> Result<()> {
+pub fn set_base_dir(dir: PathBuf) -> Result<()> {
     let canonical = if dir.exists() {
         dir.canonicalize()
             .context("Failed to extend base directory")?
@@ -58,52 +45,184 @@ pub fn save_default_base_dir(dir: PathBuf) -> Result<()> {
             .context("Failed to extend base directory")?
     };
 
-    let config = Config {
-        base_dir: Some(canonical),
-    };
-
-    let config_path = config_path();
-    if let Some(parent) = config_path.parent() {
-        std::fs::create_dir_all(parent).context("Failed to create config directory")?;
+    // setting the same directory again is fine, e.g. for several runs in one process
+    if BASE_DIR.get_or_init(|| canonical.clone()) != &canonical {
+        bail!("Base directory already set");
     }
 
-    let json = serde_json::to_string_pretty(&config).context("Failed to prettify config")?;
+    Ok(())
+}
 
-    std::fs::write(&config_path, json).context("Failed to write config file")?;
+pub fn base() -> PathBuf {
+    BASE_DIR
+        .get()
+        .cloned()
+        .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
+}
 
-    println!("Default base directory saved to: {}", config_path.display());
+pub fn set_mirror(dir: PathBuf) -> Result<()> {
+    MIRROR_DIR
+        .set(dir)
+        .map_err(|_| anyhow!("Offline mirror already set"))
+}
+
+pub fn mirror() -> Option<&'static PathBuf> {
+    MIRROR_DIR.get()
+}
+
+pub fn run_logged(cmd: &mut Command, log_file: &Path, what: &str) -> Result<()> {
+    let mut log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)
+        .context("Failed to open log file")?;
+    writeln!(log, "$ {:?}", cmd)?;
+
+    let status = cmd
+        .stdout(Stdio::from(log.try_clone()?))
+        .stderr(Stdio::from(log))
+        .status()
+        .with_context(|| format!("Failed to {}", what))?;
+
+    if !status.success() {
+        return Err(anyhow!(
+            "Failed to {} ({}), see {}",
+            what,
+            status,
+            log_file.display()
+        ));
+    }
     Ok(())
 }
 
-pub fn get_default_base_dir() -> Result<Option<PathBuf>> {
-    Ok(load_config()?.base_dir)
+pub fn clone(url: &str, dest: &Path, log_file: &Path) -> Result<()> {
+    let _lock = GIT_LOCK.lock().unwrap();
+    if dest.exists() {
+        return Ok(());
+    }
+    let name = url.trim_end_matches('/').rsplit('/').next().unwrap_or(url);
+
+    match mirror() {
+        Some(mirror) => {
+            let source = mirror.join("git").join(name);
+            if !source.exists() {
+                return Err(anyhow!(
+                    "{} is not in the offline mirror at {}, run `wispmark fetch` on a machine with network access",
+                    name,
+                    mirror.display()
+                ));
+            }
+            run_logged(
+                Command::new("cp").arg("-a").arg(&source).arg(dest),
+                log_file,
+                &format!("copy {} from the offline mirror", name),
+            )
+        }
+        None => run_logged(
+            Command::new("git").args(["clone", url]).arg(dest),
+            log_file,
+            &format!("clone {}", name),
+        ),
+    }
 }
 
-pub fn set_base_dir(dir: PathBuf) -> Result<()> {
-    let canonical = if dir.exists() {
-        dir.canonicalize()
-            .context("Failed to extend base directory")?
-    } else {
-        std::fs::create_dir_all(&dir).context("Failed to create base directory")?;
-        dir.canonicalize()
-            .context("Failed to extend base directory")?
-    };
+pub fn git_update(repo: &Path, reference: Option<&str>, log_file: &Path) -> Result<()> {
+    if mirror().is_some() {
+        return Err(anyhow!("Updating is not possible in offline mode"));
+    }
+    let _lock = GIT_LOCK.lock().unwrap();
+    let mut fetch = Command::new("git");
+    fetch.args(["fetch", "--tags", "origin"]).current_dir(repo);
+    if let Some(reference) = reference {
+        fetch.arg(reference);
+    }
+    run_logged(&mut fetch, log_file, "git fetch")?;
+    run_logged(
+        Command::new("git")
+            .args(["checkout", "--detach"])
+            .arg(if reference.is_some() { "FETCH_HEAD" } else { "origin/HEAD" })
+            .current_dir(repo),
+        log_file,
+        "git checkout",
+    )
+}
 
-    BASE_DIR
-        .set(canonical)
-        .map_err(|_| anyhow!("Base directory already set"))?;
+pub fn git_version(repo: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .args(["describe", "--tags", "--always", "--dirty"])
+        .current_dir(repo)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
 
+pub fn npm_version(dir: &Path, package: &str) -> Option<String> {
+    let manifest = dir.join("node_modules").join(package).join("package.json");
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(manifest).ok()?).ok()?;
+    manifest["version"].as_str().map(str::to_string)
+}
+
+pub fn npm_update(dir: &Path, log_file: &Path) -> Result<()> {
+    if mirror().is_some() {
+        return Err(anyhow!("Updating is not possible in offline mode"));
+    }
+    run_logged(
+        Command::new("npm").arg("update").current_dir(dir),
+        log_file,
+        "run npm update",
+    )
+}
+
+pub fn remove_dir(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)
+            .with_context(|| format!("Failed to remove {}", dir.display()))?;
+    }
     Ok(())
 }
 
-pub fn base() -> PathBuf {
-    BASE_DIR
-        .get()
-        .cloned()
-        .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
+pub fn cargo(args: &[&str]) -> Command {
+    let mut cmd = Command::new("cargo");
+    if mirror().is_some() {
+        cmd.arg("--offline");
+    }
+    cmd.args(args);
+    cmd
+}
+
+pub fn npm_install() -> Command {
+    let mut cmd = Command::new("npm");
+    cmd.arg("i");
+    if let Some(mirror) = mirror() {
+        cmd.arg("--offline").arg("--cache").arg(mirror.join("npm-cache"));
+    }
+    cmd
+}
+
+pub fn go(args: &[&str]) -> Command {
+    let mut cmd = Command::new("go");
+    cmd.args(args);
+    if mirror().is_some() {
+        cmd.env("GOFLAGS", "-mod=vendor")
+            .env("GOPROXY", "off")
+            .env("GOTOOLCHAIN", "local");
+    }
+    cmd
+}
+
+pub fn pip_flags() -> String {
+    match mirror() {
+        Some(mirror) => format!("--no-index --find-links '{}'", mirror.join("wheels").display()),
+        None => String::new(),
+    }
 }
 
-pub fn write_wispjs_files(target_dir: &PathBuf) -> Result<()> {
+pub fn write_wispjs_files(target_dir: &Path) -> Result<()> {
     use crate::embedded;
 
     let server_dir = target_dir.join("server").join("js");
@@ -112,34 +231,131 @@ pub fn write_wispjs_files(target_dir: &PathBuf) -> Result<()> {
     std::fs::create_dir_all(&server_dir).context("Failed to create wispjs server directory")?;
     std::fs::create_dir_all(&client_dir).context("Failed to create wispjs client directory")?;
 
-    let package_json_path = server_dir.join("package.json");
-    if !package_json_path.exists() {
-		println!("Copying server package.json to {}", package_json_path.display());
-        std::fs::write(package_json_path, embedded::SERVER_PACKAGE_JSON)
-            .context("Failed to write server package.json")?;
+    write_embedded(&server_dir.join("package.json"), embedded::SERVER_PACKAGE_JSON)
+        .context("Failed to write server package.json")?;
+    write_embedded(&server_dir.join("server.mjs"), embedded::SERVER_MJS)
+        .context("Failed to write server.mjs")?;
+    write_embedded(&client_dir.join("client.mjs"), embedded::CLIENT_MJS)
+        .context("Failed to write client.mjs")?;
+    write_embedded(&client_dir.join("package.json"), embedded::CLIENT_PACKAGE_JSON)
+        .context("Failed to write client package.json")?;
+    Ok(())
+}
+
+fn write_embedded(path: &Path, contents: &str) -> Result<()> {
+    if std::fs::read_to_string(path).is_ok_and(|existing| existing == contents) {
+        return Ok(());
+    }
+    println!("Copying {} to {}", path.file_name().unwrap().to_string_lossy(), path.display());
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim().to_lowercase();
+    let s = s.strip_suffix("ib").or_else(|| s.strip_suffix('b')).unwrap_or(&s);
+    let (digits, multiplier) = match s.chars().last() {
+        Some('k') => (&s[..s.len() - 1], 1024),
+        Some('m') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('g') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let amount: usize = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid size: {}", s))?;
+    if amount == 0 {
+        return Err("Size must be greater than zero".to_string());
     }
+    amount
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size too large: {}", s))
+}
 
-    let server_mjs_path = server_dir.join("server.mjs");
-    if !server_mjs_path.exists() {
-		println!("Copying server.mjs to {}", server_mjs_path.display());
-        std::fs::write(server_mjs_path, embedded::SERVER_MJS)
-            .context("Failed to write server.mjs")?;
+pub fn parse_millis(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let s = s.strip_suffix("ms").unwrap_or(s);
+    s.trim()
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("Invalid duration in milliseconds: {}", s))
+}
+
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let duration = parse_duration_or_zero(s)?;
+    if duration.is_zero() {
+        return Err("Duration must be greater than zero".to_string());
     }
+    Ok(duration)
+}
 
-    let client_mjs_path = client_dir.join("client.mjs");
-    if !client_mjs_path.exists() {
-		println!("Copying client.mjs to {}", client_mjs_path.display());
-        std::fs::write(client_mjs_path, embedded::CLIENT_MJS)
-            .context("Failed to write client.mjs")?;
+// whole seconds only, so "500ms" isn't taken for 500 minutes
+pub fn parse_duration_or_zero(s: &str) -> Result<Duration, String> {
+    let s = s.trim().to_lowercase();
+    if s.ends_with("ms") {
+        return Err(format!("Invalid duration: {}, use s, m or h", s));
     }
+    let (digits, multiplier) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 60 * 60),
+        _ => (s.as_str(), 1),
+    };
+    let amount: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid duration: {}", s))?;
+    amount
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Duration too long: {}", s))
+}
 
-    let client_package_path = client_dir.join("package.json");
-    if !client_package_path.exists() {
-		println!("Copying client package.json to {}", client_package_path.display());
-        std::fs::write(client_package_path, embedded::CLIENT_PACKAGE_JSON)
-            .context("Failed to write client package.json")?;
+pub fn format_duration(seconds: u64) -> String {
+    match seconds {
+        s if s >= 3600 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+        s => format!("{}s", s),
     }
-    Ok(())
+}
+
+pub fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut amount = bytes;
+    let mut unit = 0;
+    while unit < UNITS.len() - 1 && amount >= 1024 && amount.is_multiple_of(1024) {
+        amount /= 1024;
+        unit += 1;
+    }
+    format!("{} {}", amount, UNITS[unit])
+}
+
+pub fn format_mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / 1024.0 / 1024.0)
+}
+
+pub fn epoxy_dir() -> PathBuf {
+    base().join("epoxy-tls")
+}
+
+pub fn log_dir() -> PathBuf {
+    base().join("log")
+}
+
+pub fn runs_dir() -> PathBuf {
+    base().join("runs")
+}
+
+// a bare implementation name also selects its variants, e.g. `wisp-js` and `wisp-js (10)`
+pub fn name_matches(name: &str, names: &[String]) -> bool {
+    names.is_empty()
+        || names
+            .iter()
+            .any(|n| name == n || name.starts_with(&format!("{} ", n)))
+}
+
+pub fn log_name(name: &str) -> String {
+    name.replace('/', "_")
 }
 
 pub fn sudo() -> Result<()> {
@@ -184,7 +400,7 @@ pub async fn wait_for_tcp(port: u16, _timeout_secs: u64) -> Result<()> {
 
 pub fn kill(port: u16) -> Result<()> {
     let output = Command::new("sudo")
-        .args(&["netstat", "-tulpn"])
+        .args(["netstat", "-tulpn"])
         .output()
         .context("Failed to run netstat")?;
 
@@ -194,7 +410,7 @@ pub fn kill(port: u16) -> Result<()> {
         if let (Some(port_match), Some(pid_match)) = (cap.get(1), cap.get(2)) {
             if port_match.as_str() == port.to_string() {
                 let pid = pid_match.as_str();
-                let _ = Command::new("kill").args(&["-s", "SIGTERM", pid]).status();
+                let _ = Command::new("kill").args(["-s", "SIGTERM", pid]).status();
             }
         }
     }
@@ -202,25 +418,42 @@ pub fn kill(port: u16) -> Result<()> {
     Ok(())
 }
 
-pub async fn get_bandwidth(port: u16, duration: u64) -> Result<f64> {
+pub async fn get_bandwidth(port: u16, duration: u64) -> Result<Bandwidth> {
+    let up_filter = format!("dst port {}", port);
+    let down_filter = format!("src port {}", port);
+    let (up, down) = tokio::try_join!(
+        measure_bytes(&up_filter, duration),
+        measure_bytes(&down_filter, duration),
+    )?;
+
+    let mib = 1024.0 * 1024.0;
+    Ok(Bandwidth {
+        up: up / mib,
+        down: down / mib,
+    })
+}
+
+async fn measure_bytes(filter: &str, duration: u64) -> Result<f64> {
     let start = Instant::now();
 
-    let output = Command::new("timeout")
+    let output = tokio::process::Command::new("timeout")
         .arg((duration * 2).to_string())
         .arg("sudo")
         .arg("iftop")
-        .args(&[
+        .args([
             "-i",
             "lo",
             "-f",
-            &format!("port {}", port),
+            filter,
             "-t",
             "-s",
             &duration.to_string(),
             "-B",
         ])
         .stderr(Stdio::null())
+        .kill_on_drop(true)
         .output()
+        .await
         .context("Failed to run iftop")?;
 
     let end = Instant::now();
@@ -261,7 +494,7 @@ fn is_wsl() -> bool {
 pub fn get_cpu_info() -> Result<String> {
     let cpu_name = if is_wsl() {
         let output = Command::new("/mnt/c/Windows/System32/WindowsPowershell/v1.0/powershell.exe")
-            .args(&[
+            .args([
                 "-command",
                 "Get-CimInstance -ClassName Win32_Processor | Select-Object -ExpandProperty Name",
             ])
@@ -297,21 +530,24 @@ pub fn get_cpu_info() -> Result<String> {
 }
 
 pub fn run(
+    role: Role,
     command: &str,
     args: &[&str],
     working_dir: Option<&PathBuf>,
-    log_file: &PathBuf,
-) -> Result<std::process::Child> {
+    log_file: &Path,
+) -> Result<tokio::process::Child> {
     let log = std::fs::File::create(log_file).context("Failed to create log file")?;
 
-    let mut cmd = Command::new(command);
+    let mut cmd = tokio::process::Command::new(command);
     cmd.args(args)
         .stdout(Stdio::from(log.try_clone()?))
-        .stderr(Stdio::from(log));
+        .stderr(Stdio::from(log))
+        .kill_on_drop(true);
 
     if let Some(dir) = working_dir {
         cmd.current_dir(dir);
     }
+    placement::apply(role, cmd.as_std_mut());
 
     cmd.spawn().context("Failed to run command")
 }